@@ -38,6 +38,7 @@ fn start_counter_1(duration_nano: u64) -> TaskId {
             delay_nano: duration_nano,
             interval_nano: duration_nano,
             iterations: Iterations::Infinite,
+            ..Default::default()
         },
     );
 
@@ -67,6 +68,7 @@ fn start_counter_2(duration_nano: u64, step: u64) -> TaskId {
             delay_nano: duration_nano,
             interval_nano: duration_nano,
             iterations: Iterations::Infinite,
+            ..Default::default()
         },
     );
 
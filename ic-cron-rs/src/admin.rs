@@ -0,0 +1,8 @@
+use ic_cdk::export::candid::Principal;
+
+/// The default guard of `implement_cron_admin!()` endpoints
+pub fn is_controller(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+
+    unsafe { ic0::is_controller(bytes.as_ptr() as i32, bytes.len() as i32) != 0 }
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod macros;
 pub mod task_scheduler;
 pub mod types;
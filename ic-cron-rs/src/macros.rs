@@ -1,11 +1,40 @@
+/// Accepts optional flags, which can be combined in any order:
+/// * `metrics_http` - serves metrics in the Prometheus format from an `http_request` query method
+/// * `observer = $observer` - registers a `CronObserver` each time the state is put
 #[macro_export]
 macro_rules! implement_cron {
     () => {
+        $crate::implement_cron!(@state);
+    };
+    (metrics_http) => {
+        $crate::implement_cron!(@state);
+        $crate::implement_cron!(@metrics_http);
+    };
+    (observer = $observer:expr) => {
+        $crate::implement_cron!(@state $observer);
+    };
+    (metrics_http, observer = $observer:expr) => {
+        $crate::implement_cron!(@state $observer);
+        $crate::implement_cron!(@metrics_http);
+    };
+    (observer = $observer:expr, metrics_http) => {
+        $crate::implement_cron!(metrics_http, observer = $observer);
+    };
+    (@metrics_http) => {
+        /// Serves cron metrics in the Prometheus format at `/metrics`
+        #[ic_cdk::query]
+        fn http_request(request: ic_cron::types::HttpRequest) -> ic_cron::types::HttpResponse {
+            get_cron_state()
+                .get_metrics(ic_cdk::api::time())
+                .http_response(&request)
+        }
+    };
+    (@state $($observer:expr)?) => {
         pub static mut _CRON_STATE: Option<ic_cron::task_scheduler::TaskScheduler> = None;
 
         pub fn get_cron_state() -> &'static mut ic_cron::task_scheduler::TaskScheduler {
             unsafe {
-                match _CRON_STATE.as_mut() {
+                match (*std::ptr::addr_of_mut!(_CRON_STATE)).as_mut() {
                     Some(cron) => cron,
                     None => {
                         _put_cron_state(Some(ic_cron::task_scheduler::TaskScheduler::default()));
@@ -16,24 +45,171 @@ macro_rules! implement_cron {
         }
 
         pub fn _take_cron_state() -> Option<ic_cron::task_scheduler::TaskScheduler> {
-            unsafe { _CRON_STATE.take() }
+            unsafe { (*std::ptr::addr_of_mut!(_CRON_STATE)).take() }
         }
 
         pub fn _put_cron_state(state: Option<ic_cron::task_scheduler::TaskScheduler>) {
             unsafe {
                 _CRON_STATE = state;
             }
+
+            $(
+                if let Some(cron) = unsafe { (*std::ptr::addr_of_mut!(_CRON_STATE)).as_mut() } {
+                    cron.set_observer($observer);
+                }
+            )?
         }
 
         pub fn cron_enqueue<Payload: ic_cdk::export::candid::CandidType>(
             payload: Payload,
             scheduling_options: ic_cron::types::SchedulingOptions,
-        ) -> ic_cdk::export::candid::Result<ic_cron::types::TaskId> {
-            let cron = get_cron_state();
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            cron_enqueue_with_options(
+                payload,
+                scheduling_options,
+                ic_cron::types::EnqueueOptions::default(),
+            )
+        }
+
+        pub fn cron_enqueue_with_options<Payload: ic_cdk::export::candid::CandidType>(
+            payload: Payload,
+            scheduling_options: ic_cron::types::SchedulingOptions,
+            enqueue_options: ic_cron::types::EnqueueOptions,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().enqueue_with_options(
+                payload,
+                scheduling_options,
+                enqueue_options,
+                ic_cdk::api::time(),
+            )
+        }
+
+        pub fn cron_enqueue_owned<Payload: ic_cdk::export::candid::CandidType>(
+            payload: Payload,
+            scheduling_options: ic_cron::types::SchedulingOptions,
+            enqueue_options: ic_cron::types::EnqueueOptions,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            cron_enqueue_with_options(
+                payload,
+                scheduling_options,
+                ic_cron::types::EnqueueOptions {
+                    owner: Some(ic_cdk::caller()),
+                    ..enqueue_options
+                },
+            )
+        }
+
+        pub fn cron_upsert<Payload: ic_cdk::export::candid::CandidType>(
+            key: String,
+            payload: Payload,
+            scheduling_options: ic_cron::types::SchedulingOptions,
+            enqueue_options: ic_cron::types::EnqueueOptions,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().upsert(
+                key,
+                payload,
+                scheduling_options,
+                enqueue_options,
+                ic_cdk::api::time(),
+            )
+        }
+
+        pub fn cron_reschedule(
+            task_id: ic_cron::types::TaskId,
+            scheduling_options: ic_cron::types::SchedulingOptions,
+            iterations_policy: ic_cron::types::IterationsPolicy,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().reschedule(
+                task_id,
+                scheduling_options,
+                iterations_policy,
+                ic_cdk::api::time(),
+            )
+        }
 
-            let id = cron.enqueue(payload, scheduling_options, ic_cdk::api::time())?;
+        pub fn cron_update_payload<Payload: ic_cdk::export::candid::CandidType>(
+            task_id: ic_cron::types::TaskId,
+            payload: Payload,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().update_payload(task_id, payload)
+        }
 
-            Ok(id)
+        pub fn cron_dequeue_owned(
+            task_id: ic_cron::types::TaskId,
+        ) -> ic_cron::types::CronResult<ic_cron::types::ScheduledTask> {
+            get_cron_state().dequeue_owned(task_id, &ic_cdk::caller())
+        }
+
+        pub fn cron_reschedule_owned(
+            task_id: ic_cron::types::TaskId,
+            scheduling_options: ic_cron::types::SchedulingOptions,
+            iterations_policy: ic_cron::types::IterationsPolicy,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().reschedule_owned(
+                task_id,
+                scheduling_options,
+                iterations_policy,
+                &ic_cdk::caller(),
+                ic_cdk::api::time(),
+            )
+        }
+
+        pub fn cron_update_payload_owned<Payload: ic_cdk::export::candid::CandidType>(
+            task_id: ic_cron::types::TaskId,
+            payload: Payload,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().update_payload_owned(task_id, payload, &ic_cdk::caller())
+        }
+
+        pub fn cron_throttle<Payload: ic_cdk::export::candid::CandidType>(
+            key: String,
+            payload: Payload,
+            window_nano: u64,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().throttle(key, payload, window_nano, ic_cdk::api::time())
+        }
+
+        pub fn cron_throttle_owned<Payload: ic_cdk::export::candid::CandidType>(
+            key: String,
+            payload: Payload,
+            window_nano: u64,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().throttle_owned(
+                key,
+                payload,
+                window_nano,
+                &ic_cdk::caller(),
+                ic_cdk::api::time(),
+            )
+        }
+
+        pub fn cron_set_rate_limit(
+            group: String,
+            limit: ic_cron::types::RateLimit,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().set_rate_limit(group, limit, ic_cdk::api::time())
+        }
+
+        pub fn cron_debounce<Payload: ic_cdk::export::candid::CandidType>(
+            key: String,
+            payload: Payload,
+            delay_nano: u64,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().debounce(key, payload, delay_nano, ic_cdk::api::time())
+        }
+
+        pub fn cron_debounce_owned<Payload: ic_cdk::export::candid::CandidType>(
+            key: String,
+            payload: Payload,
+            delay_nano: u64,
+        ) -> ic_cron::types::CronResult<ic_cron::types::TaskId> {
+            get_cron_state().debounce_owned(
+                key,
+                payload,
+                delay_nano,
+                &ic_cdk::caller(),
+                ic_cdk::api::time(),
+            )
         }
 
         pub fn cron_dequeue(
@@ -42,21 +218,263 @@ macro_rules! implement_cron {
             get_cron_state().dequeue(task_id)
         }
 
+        pub async fn cron_init_seed() {
+            if get_cron_state().seed.is_some() {
+                return;
+            }
+
+            let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+                .await
+                .expect("Unable to fetch randomness for the cron seed");
+
+            if get_cron_state().seed.is_none() {
+                get_cron_state().set_seed(&random_bytes);
+            }
+        }
+
+        pub fn cron_pause(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().pause(task_id)
+        }
+
+        pub fn cron_resume(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().resume(task_id, ic_cdk::api::time())
+        }
+
+        pub fn cron_get_tasks_by_tag(tag: &str) -> Vec<ic_cron::types::ScheduledTask> {
+            get_cron_state().get_tasks_by_tag_cloned(tag)
+        }
+
+        pub fn cron_dequeue_by_tag(tag: &str) -> Vec<ic_cron::types::ScheduledTask> {
+            get_cron_state().dequeue_by_tag(tag)
+        }
+
+        pub fn cron_pause_by_tag(tag: &str) -> usize {
+            get_cron_state().pause_by_tag(tag)
+        }
+
+        pub fn cron_resume_by_tag(tag: &str) -> usize {
+            get_cron_state().resume_by_tag(tag, ic_cdk::api::time())
+        }
+
+        pub fn cron_next_run(task_id: ic_cron::types::TaskId) -> Option<u64> {
+            get_cron_state().next_run(task_id)
+        }
+
+        pub fn cron_upcoming(
+            from: u64,
+            to: u64,
+            limit: usize,
+        ) -> Vec<ic_cron::types::TaskTimestamp> {
+            get_cron_state().upcoming(from, to, limit)
+        }
+
+        pub fn cron_get_runs(task_id: ic_cron::types::TaskId) -> Vec<ic_cron::types::RunLogEntry> {
+            get_cron_state().get_runs(task_id)
+        }
+
+        pub fn cron_get_recent_runs(limit: usize) -> Vec<ic_cron::types::RunLogEntry> {
+            get_cron_state().get_recent_runs(limit)
+        }
+
+        pub fn cron_get_metrics() -> ic_cron::types::CronMetrics {
+            get_cron_state().get_metrics(ic_cdk::api::time())
+        }
+
+        pub fn cron_query_tasks(query: ic_cron::types::TaskQuery) -> ic_cron::types::TaskPage {
+            get_cron_state().query_tasks(&query)
+        }
+
+        pub fn cron_trigger_now(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<()> {
+            get_cron_state().trigger_now(task_id, ic_cdk::api::time())
+        }
+
+        pub fn cron_get_events(from_seq: u64, limit: usize) -> Vec<ic_cron::types::CronEvent> {
+            get_cron_state().get_events(from_seq, limit)
+        }
+
         pub fn cron_ready_tasks() -> Vec<ic_cron::types::ScheduledTask> {
             get_cron_state().iterate(ic_cdk::api::time())
         }
+
+        pub fn cron_complete(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().complete(task_id, ic_cdk::api::time())
+        }
+
+        pub fn cron_ready_tasks_limited(max_tasks: usize) -> Vec<ic_cron::types::ScheduledTask> {
+            get_cron_state().iterate_limited(ic_cdk::api::time(), max_tasks)
+        }
+
+        pub fn cron_ready_tasks_within_budget(
+            max_tasks: usize,
+            max_instructions: u64,
+        ) -> Vec<ic_cron::types::ScheduledTask> {
+            get_cron_state().iterate_within_budget(ic_cdk::api::time(), max_tasks, || {
+                ic_cdk::api::instruction_counter() < max_instructions
+            })
+        }
+
+        pub fn cron_execute_ready_tasks<F: FnMut(ic_cron::types::ScheduledTask)>(
+            max_tasks: usize,
+            max_instructions: u64,
+            mut handler: F,
+        ) -> usize {
+            get_cron_state().promote_ready_tasks_within_budget(ic_cdk::api::time(), || {
+                ic_cdk::api::instruction_counter() < max_instructions
+            });
+
+            let mut executed = 0;
+
+            while executed < max_tasks && ic_cdk::api::instruction_counter() < max_instructions {
+                match get_cron_state().pop_ready_task(ic_cdk::api::time()) {
+                    Some(task) => {
+                        let task_id = task.id;
+
+                        handler(task);
+                        get_cron_state().report_success(task_id);
+                        // the handler could have dequeued the task
+                        let _ = cron_complete(task_id);
+                    }
+                    None => break,
+                };
+
+                executed += 1;
+            }
+
+            executed
+        }
+    };
+}
+
+/// Implements an update method, which executes a single task with `$handler`, and
+/// `cron_dispatch_ready_tasks()`, which executes each ready task in a separate self-call. This way a
+/// trap in one task only rolls back this task's execution. Should be used alongside `implement_cron!()`.
+#[macro_export]
+macro_rules! implement_cron_executor {
+    ($handler:path) => {
+        #[ic_cdk::update(name = "_cron_execute_task")]
+        fn _cron_execute_task(task: ic_cron::types::ScheduledTask) {
+            if ic_cdk::caller() != ic_cdk::id() {
+                ic_cdk::trap("Only the canister itself can execute cron tasks");
+            }
+
+            $handler(task);
+        }
+
+        pub fn cron_dispatch_ready_tasks(max_tasks: usize) -> usize {
+            let tasks = cron_ready_tasks_limited(max_tasks);
+            let count = tasks.len();
+
+            for task in tasks {
+                ic_cdk::spawn(async move {
+                    let task_id = task.id;
+                    let result: ic_cdk::api::call::CallResult<()> =
+                        ic_cdk::call(ic_cdk::id(), "_cron_execute_task", (task,)).await;
+
+                    match result {
+                        Ok(_) => get_cron_state().report_success(task_id),
+                        Err((_, error)) => {
+                            get_cron_state().report_failure(task_id, error);
+                        }
+                    }
+
+                    let _ = cron_complete(task_id);
+                });
+            }
+
+            count
+        }
+
+        pub fn cron_release_quarantine(
+            task_id: ic_cron::types::TaskId,
+        ) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().release_quarantine(task_id, ic_cdk::api::time())
+        }
+    };
+}
+
+/// Implements admin endpoints to inspect and manage tasks, which can only be called by principals
+/// accepted by `$guard: fn(&Principal) -> bool` (canister controllers by default). Should be used
+/// alongside `implement_cron!()`, see `cron_admin.did` for their Candid interface.
+#[macro_export]
+macro_rules! implement_cron_admin {
+    () => {
+        $crate::implement_cron_admin!(ic_cron::admin::is_controller);
+    };
+    ($guard:path) => {
+        fn _cron_admin_guard() -> Result<(), String> {
+            if $guard(&ic_cdk::caller()) {
+                Ok(())
+            } else {
+                Err(String::from(
+                    "The caller is not allowed to manage cron tasks",
+                ))
+            }
+        }
+
+        #[ic_cdk::query(guard = "_cron_admin_guard")]
+        fn cron_admin_list_tasks(query: ic_cron::types::TaskQuery) -> ic_cron::types::TaskPage {
+            get_cron_state().query_tasks(&query)
+        }
+
+        #[ic_cdk::query(guard = "_cron_admin_guard")]
+        fn cron_admin_get_task(
+            task_id: ic_cron::types::TaskId,
+        ) -> Option<ic_cron::types::ScheduledTask> {
+            get_cron_state().get_task_by_id_cloned(&task_id)
+        }
+
+        #[ic_cdk::update(guard = "_cron_admin_guard")]
+        fn cron_admin_cancel(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<()> {
+            get_cron_state()
+                .dequeue(task_id)
+                .map(|_| ())
+                .ok_or(ic_cron::types::CronError::TaskNotFound(task_id))
+        }
+
+        #[ic_cdk::update(guard = "_cron_admin_guard")]
+        fn cron_admin_pause(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().pause(task_id)
+        }
+
+        #[ic_cdk::update(guard = "_cron_admin_guard")]
+        fn cron_admin_resume(task_id: ic_cron::types::TaskId) -> ic_cron::types::CronResult<bool> {
+            get_cron_state().resume(task_id, ic_cdk::api::time())
+        }
+
+        #[ic_cdk::update(guard = "_cron_admin_guard")]
+        fn cron_admin_trigger_now(
+            task_id: ic_cron::types::TaskId,
+        ) -> ic_cron::types::CronResult<()> {
+            get_cron_state().trigger_now(task_id, ic_cdk::api::time())
+        }
+
+        #[ic_cdk::query(guard = "_cron_admin_guard")]
+        fn cron_admin_stats() -> ic_cron::types::CronMetrics {
+            get_cron_state().get_metrics(ic_cdk::api::time())
+        }
     };
 }
 
 #[cfg(test)]
+#[allow(dead_code)]
 mod tests {
     use crate as ic_cron;
-    use crate::implement_cron;
     use crate::task_scheduler::TaskScheduler;
+    use crate::types::{CronObserver, ScheduledTask, TaskEvent};
     use ic_cdk::storage::{stable_restore, stable_save};
     use ic_cdk_macros::{heartbeat, post_upgrade, pre_upgrade};
 
-    implement_cron!();
+    implement_cron!(metrics_http, observer = Observer);
+    implement_cron_executor!(execute_task);
+    implement_cron_admin!();
+
+    fn execute_task(_task: ScheduledTask) {}
+
+    struct Observer;
+
+    impl CronObserver for Observer {
+        fn on_event(&self, _task: &ScheduledTask, _event: TaskEvent) {}
+    }
 
     #[pre_upgrade]
     fn pre_upgrade_hook() {
@@ -75,10 +493,13 @@ mod tests {
 
     #[heartbeat]
     fn tick() {
-        let tasks = cron_ready_tasks();
+        let _tasks = cron_ready_tasks_limited(100);
+
+        cron_execute_ready_tasks(100, 1_000_000_000, |_task| {});
     }
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn no_op() {
         assert!(true);
     }
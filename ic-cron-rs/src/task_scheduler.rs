@@ -1,18 +1,85 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::cmp::max;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::rc::Rc;
 
-use ic_cdk::export::candid::{CandidType, Deserialize, Result as CandidResult};
+use ic_cdk::export::candid::types::{Serializer, Type};
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use ic_cdk::export::serde::Deserializer;
 
 use crate::types::{
-    Iterations, ScheduledTask, SchedulingOptions, TaskExecutionQueue, TaskId, TaskTimestamp,
+    CronError, CronEvent, CronMetrics, CronObserver, CronResult, EnqueueOptions, EventLog,
+    FairScheduling, Iterations, IterationsPolicy, RateLimit, RunCounters, RunInfo, RunLog,
+    RunLogEntry, RunOutcome, ScheduledTask, SchedulingMode, SchedulingOptions, TaskCursor,
+    TaskEvent, TaskExecutionQueue, TaskId, TaskOrder, TaskPage, TaskQuery, TaskQuota,
+    TaskTimestamp, ThrottleState, TokenBucket,
 };
 
+pub const DEFAULT_QUARANTINE_THRESHOLD: u32 = 3;
+pub const DEFAULT_RUN_LOG_CAPACITY: u64 = 1000;
+pub const DEFAULT_PAGE_SIZE: u64 = 100;
+pub const MAX_PAGE_SIZE: u64 = 1000;
+pub const MAX_EVENTS_PAGE_SIZE: usize = 1000;
+
 #[derive(Default, CandidType, Deserialize, Clone)]
 pub struct TaskScheduler {
     pub tasks: HashMap<TaskId, ScheduledTask>,
     pub task_id_counter: TaskId,
 
     pub queue: TaskExecutionQueue,
+
+    pub quarantine_threshold: Option<u32>,
+    pub seed: Option<u64>,
+    /// Whether runs caused by `trigger_now()` consume `Iterations::Exact`
+    pub count_manual_runs: Option<bool>,
+
+    pub debounced: Option<HashMap<String, TaskId>>,
+    pub throttled: Option<HashMap<String, ThrottleState>>,
+    pub rate_limits: Option<HashMap<String, TokenBucket>>,
+    pub idempotency_keys: Option<HashMap<String, TaskId>>,
+    pub tag_index: Option<HashMap<String, BTreeSet<TaskId>>>,
+    pub group_index: Option<HashMap<String, BTreeSet<TaskId>>>,
+    pub owner_index: Option<HashMap<Principal, BTreeSet<TaskId>>>,
+
+    pub default_quota: Option<TaskQuota>,
+    pub quotas: Option<HashMap<Principal, TaskQuota>>,
+
+    pub fair_scheduling: Option<FairScheduling>,
+
+    pub run_log: Option<RunLog>,
+    pub run_counters: Option<RunCounters>,
+    pub event_log: Option<EventLog>,
+
+    observer: ObserverSlot,
+}
+
+/// Observers can't be persisted, so this slot is always serialized as `null`
+#[derive(Default, Clone)]
+struct ObserverSlot(Option<Rc<dyn CronObserver>>);
+
+impl CandidType for ObserverSlot {
+    fn _ty() -> Type {
+        Option::<()>::ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        None::<()>.idl_serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ObserverSlot {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<()>::deserialize(deserializer)?;
+
+        Ok(Self::default())
+    }
 }
 
 impl TaskScheduler {
@@ -21,384 +88,3739 @@ impl TaskScheduler {
         payload: TaskPayload,
         scheduling_interval: SchedulingOptions,
         timestamp: u64,
-    ) -> CandidResult<TaskId> {
+    ) -> CronResult<TaskId> {
+        self.enqueue_with_options(
+            payload,
+            scheduling_interval,
+            EnqueueOptions::default(),
+            timestamp,
+        )
+    }
+
+    pub fn enqueue_with_options<TaskPayload: CandidType>(
+        &mut self,
+        payload: TaskPayload,
+        scheduling_interval: SchedulingOptions,
+        enqueue_options: EnqueueOptions,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        if let Some(task_id) = enqueue_options
+            .idempotency_key
+            .as_ref()
+            .and_then(|key| self.find_by_idempotency_key(key))
+        {
+            return Ok(task_id);
+        }
+
+        scheduling_interval.validate(timestamp)?;
+
+        if let Some(owner) = &enqueue_options.owner {
+            self.check_quota(owner, &scheduling_interval, 1)?;
+        }
+
+        // the payload is encoded first, so a failed enqueue doesn't consume an id
+        let mut task = ScheduledTask::new(0, payload, timestamp, None, scheduling_interval)?;
         let id = self.generate_task_id();
-        let task = ScheduledTask::new(id, payload, timestamp, None, scheduling_interval)?;
-
-        match task.scheduling_options.iterations {
-            Iterations::Exact(times) => {
-                if times > 0 {
-                    self.queue.push(TaskTimestamp {
-                        task_id: id,
-                        timestamp: timestamp + task.scheduling_options.delay_nano,
-                    })
-                }
-            }
-            Iterations::Infinite => self.queue.push(TaskTimestamp {
-                task_id: id,
-                timestamp: timestamp + task.scheduling_options.delay_nano,
-            }),
-        };
+        task.id = id;
+        task.group = enqueue_options.group;
+        task.tags = enqueue_options.tags.filter(|tags| !tags.is_empty());
+        task.owner = enqueue_options.owner;
+        task.namespace = enqueue_options.namespace;
+
+        if let Some(key) = enqueue_options.idempotency_key {
+            self.idempotency_keys
+                .get_or_insert_with(HashMap::new)
+                .insert(key.clone(), id);
+
+            task.idempotency_key = Some(key);
+        }
+
+        Self::schedule_first_run(&mut self.queue, self.seed, &mut task, timestamp);
 
+        self.index_task(&task);
+        self.notify(&task, TaskEvent::Enqueued);
         self.tasks.insert(id, task);
 
         Ok(id)
     }
 
-    pub fn iterate(&mut self, timestamp: u64) -> Vec<ScheduledTask> {
-        let mut tasks = vec![];
+    /// Same as `enqueue_with_options()`, but if there is already a task with this idempotency `key`,
+    /// replaces its payload, schedule (as if it was enqueued right now), group, tags and namespace
+    /// keeping its id and owner. Returns `Err(NotOwner)` if `enqueue_options.owner` is set, but the
+    /// task is owned by someone else.
+    pub fn upsert<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        scheduling_interval: SchedulingOptions,
+        mut enqueue_options: EnqueueOptions,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        let task_id = match self.find_by_idempotency_key(&key) {
+            Some(task_id) => task_id,
+            None => {
+                enqueue_options.idempotency_key = Some(key);
 
-        for task_id in self
-            .queue
-            .pop_ready(timestamp)
-            .into_iter()
-            .map(|it| it.task_id)
-        {
-            let mut should_remove = false;
-
-            match self.tasks.entry(task_id) {
-                Entry::Occupied(mut entry) => {
-                    let task = entry.get_mut();
-
-                    match task.scheduling_options.iterations {
-                        Iterations::Infinite => {
-                            let new_rescheduled_at = if task.delay_passed {
-                                if let Some(rescheduled_at) = task.rescheduled_at {
-                                    rescheduled_at + task.scheduling_options.interval_nano
-                                } else {
-                                    task.scheduled_at + task.scheduling_options.interval_nano
-                                }
-                            } else {
-                                task.delay_passed = true;
-
-                                if let Some(rescheduled_at) = task.rescheduled_at {
-                                    rescheduled_at + task.scheduling_options.delay_nano
-                                } else {
-                                    task.scheduled_at + task.scheduling_options.delay_nano
-                                }
-                            };
-
-                            task.rescheduled_at = Some(new_rescheduled_at);
-
-                            self.queue.push(TaskTimestamp {
-                                task_id,
-                                timestamp: new_rescheduled_at
-                                    + task.scheduling_options.interval_nano,
-                            });
-                        }
-                        Iterations::Exact(times_left) => {
-                            if times_left > 1 {
-                                let new_rescheduled_at = if task.delay_passed {
-                                    if let Some(rescheduled_at) = task.rescheduled_at {
-                                        rescheduled_at + task.scheduling_options.interval_nano
-                                    } else {
-                                        task.scheduled_at + task.scheduling_options.interval_nano
-                                    }
-                                } else {
-                                    task.delay_passed = true;
-
-                                    if let Some(rescheduled_at) = task.rescheduled_at {
-                                        rescheduled_at + task.scheduling_options.delay_nano
-                                    } else {
-                                        task.scheduled_at + task.scheduling_options.delay_nano
-                                    }
-                                };
-
-                                task.rescheduled_at = Some(new_rescheduled_at);
-
-                                self.queue.push(TaskTimestamp {
-                                    task_id,
-                                    timestamp: new_rescheduled_at
-                                        + task.scheduling_options.interval_nano,
-                                });
-
-                                task.scheduling_options.iterations =
-                                    Iterations::Exact(times_left - 1);
-                            } else {
-                                should_remove = true;
-                            }
-                        }
-                    };
-
-                    tasks.push(task.clone());
+                return self.enqueue_with_options(
+                    payload,
+                    scheduling_interval,
+                    enqueue_options,
+                    timestamp,
+                );
+            }
+        };
+
+        if let Some(caller) = &enqueue_options.owner {
+            self.check_owner(task_id, caller)?;
+        }
+
+        scheduling_interval.validate(timestamp)?;
+
+        if let Some(owner) = self.tasks[&task_id].owner {
+            self.check_quota(&owner, &scheduling_interval, 0)?;
+        }
+
+        self.update_payload(task_id, payload)?;
+        self.reschedule(
+            task_id,
+            scheduling_interval,
+            IterationsPolicy::Reset,
+            timestamp,
+        )?;
+        let task = self.tasks.remove(&task_id).unwrap();
+        self.unindex_task(&task);
+
+        let task = ScheduledTask {
+            group: enqueue_options.group,
+            tags: enqueue_options.tags.filter(|tags| !tags.is_empty()),
+            namespace: enqueue_options.namespace,
+            ..task
+        };
+        self.index_task(&task);
+        self.tasks.insert(task_id, task);
+
+        Ok(task_id)
+    }
+
+    /// Replaces scheduling options of the task keeping its id, the task is rescheduled as if it was
+    /// enqueued right now
+    pub fn reschedule(
+        &mut self,
+        task_id: TaskId,
+        scheduling_options: SchedulingOptions,
+        iterations_policy: IterationsPolicy,
+        timestamp: u64,
+    ) -> CronResult<()> {
+        scheduling_options.validate(timestamp)?;
+
+        let task = self
+            .tasks
+            .get(&task_id)
+            .ok_or(CronError::TaskNotFound(task_id))?;
+
+        let iterations = match iterations_policy {
+            IterationsPolicy::Reset => scheduling_options.iterations,
+            IterationsPolicy::Keep => task.scheduling_options.iterations,
+        };
+        let scheduling_options = SchedulingOptions {
+            iterations,
+            ..scheduling_options
+        };
+
+        if let Some(owner) = task.owner {
+            self.check_quota(&owner, &scheduling_options, 0)?;
+        }
+
+        let task = self.tasks.get_mut(&task_id).unwrap();
+        task.scheduling_options = scheduling_options;
+
+        self.queue.remove(task_id);
+        Self::schedule_first_run(&mut self.queue, self.seed, task, timestamp);
+
+        Self::emit(
+            &mut self.event_log,
+            &self.observer,
+            &self.tasks[&task_id],
+            TaskEvent::Rescheduled,
+            None,
+        );
+
+        Ok(())
+    }
+
+    pub fn update_payload<TaskPayload: CandidType>(
+        &mut self,
+        task_id: TaskId,
+        payload: TaskPayload,
+    ) -> CronResult<()> {
+        self.tasks
+            .get_mut(&task_id)
+            .ok_or(CronError::TaskNotFound(task_id))?
+            .set_payload(payload)
+    }
+
+    pub fn find_by_idempotency_key(&self, key: &str) -> Option<TaskId> {
+        self.idempotency_keys
+            .as_ref()
+            .and_then(|keys| keys.get(key))
+            .copied()
+            .filter(|task_id| self.tasks.contains_key(task_id))
+    }
+
+    /// Enqueues a task which fires once after `delay_nano`. If there is already such a task with the
+    /// same `key` which didn't fire yet, replaces its payload and postpones it instead.
+    pub fn debounce<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        delay_nano: u64,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        self.debounce_with_owner(key, payload, delay_nano, None, timestamp)
+    }
+
+    /// Same as `debounce()`, but the task is owned by `caller` and subject to its quota. Returns
+    /// `Err(NotOwner)` if a task with the same `key` is owned by someone else.
+    pub fn debounce_owned<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        delay_nano: u64,
+        caller: &Principal,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        if let Some(task_id) = self.find_debounced(&key) {
+            self.check_owner(task_id, caller)?;
+        }
+
+        self.debounce_with_owner(key, payload, delay_nano, Some(*caller), timestamp)
+    }
+
+    fn debounce_with_owner<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        delay_nano: u64,
+        owner: Option<Principal>,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        let run_at = timestamp.checked_add(delay_nano).ok_or_else(|| {
+            CronError::InvalidSchedule(String::from("Scheduled timestamps overflow u64"))
+        })?;
+
+        if let Some(task_id) = self.find_debounced(&key) {
+            let task = &self.tasks[&task_id];
+
+            if let Some(owner) = task.owner {
+                self.check_quota(&owner, &task.scheduling_options, 0)?;
+            }
+
+            let task = self.tasks.get_mut(&task_id).unwrap();
+            task.set_payload(payload)?;
+            task.scheduled_at = timestamp;
+            task.scheduling_options.delay_nano = delay_nano;
+            task.triggered_at = None;
+
+            self.queue.remove(task_id);
+            Self::push_run(&mut self.queue, self.seed, task, run_at);
+
+            Self::emit(
+                &mut self.event_log,
+                &self.observer,
+                &self.tasks[&task_id],
+                TaskEvent::Rescheduled,
+                None,
+            );
+
+            return Ok(task_id);
+        }
+
+        let task_id = self.enqueue_with_options(
+            payload,
+            SchedulingOptions {
+                delay_nano,
+                ..Default::default()
+            },
+            EnqueueOptions {
+                owner,
+                ..Default::default()
+            },
+            timestamp,
+        )?;
+
+        self.tasks.get_mut(&task_id).unwrap().debounce_key = Some(key.clone());
+        self.debounced
+            .get_or_insert_with(HashMap::new)
+            .insert(key, task_id);
+
+        Ok(task_id)
+    }
+
+    /// Enqueues a task which fires once, but not earlier than `window_nano` after the previous task
+    /// with the same `key`. If there is already such a task which didn't fire yet, replaces its payload
+    /// instead, so a burst of calls yields at most one execution per window.
+    pub fn throttle<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        window_nano: u64,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        self.throttle_with_owner(key, payload, window_nano, None, timestamp)
+    }
+
+    /// Same as `throttle()`, but the task is owned by `caller` and subject to its quota. Returns
+    /// `Err(NotOwner)` if a pending task with the same `key` is owned by someone else.
+    pub fn throttle_owned<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        window_nano: u64,
+        caller: &Principal,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        let pending = self
+            .throttled
+            .as_ref()
+            .and_then(|throttled| throttled.get(&key))
+            .map(|previous| previous.task_id)
+            .filter(|task_id| self.tasks.contains_key(task_id));
+
+        if let Some(task_id) = pending {
+            self.check_owner(task_id, caller)?;
+        }
+
+        self.throttle_with_owner(key, payload, window_nano, Some(*caller), timestamp)
+    }
+
+    fn throttle_with_owner<TaskPayload: CandidType>(
+        &mut self,
+        key: String,
+        payload: TaskPayload,
+        window_nano: u64,
+        owner: Option<Principal>,
+        timestamp: u64,
+    ) -> CronResult<TaskId> {
+        self.prune_throttled(timestamp);
+
+        let previous = self
+            .throttled
+            .as_ref()
+            .and_then(|throttled| throttled.get(&key))
+            .copied();
+
+        if let Some(previous) = previous {
+            if let Some(task) = self.tasks.get(&previous.task_id) {
+                if let Some(owner) = task.owner {
+                    self.check_quota(&owner, &task.scheduling_options, 0)?;
+                }
+
+                self.update_payload(previous.task_id, payload)?;
+
+                return Ok(previous.task_id);
+            }
+        }
+
+        let run_at = previous.map_or(timestamp, |previous| {
+            max(timestamp, previous.last_run_at.saturating_add(window_nano))
+        });
+
+        let task_id = self.enqueue_with_options(
+            payload,
+            SchedulingOptions {
+                start_at: Some(run_at),
+                ..Default::default()
+            },
+            EnqueueOptions {
+                owner,
+                ..Default::default()
+            },
+            timestamp,
+        )?;
+
+        self.throttled.get_or_insert_with(HashMap::new).insert(
+            key,
+            ThrottleState {
+                last_run_at: run_at,
+                task_id,
+                window_ends_at: Some(run_at.saturating_add(window_nano)),
+            },
+        );
+
+        Ok(task_id)
+    }
+
+    fn find_debounced(&self, key: &str) -> Option<TaskId> {
+        self.debounced
+            .as_ref()
+            .and_then(|debounced| debounced.get(key))
+            .copied()
+            .filter(|task_id| self.tasks.contains_key(task_id))
+    }
+
+    /// Forgets throttle keys which tasks already fired and which windows are over
+    fn prune_throttled(&mut self, timestamp: u64) {
+        let tasks = &self.tasks;
+
+        if let Some(throttled) = self.throttled.as_mut() {
+            throttled.retain(|_, state| {
+                tasks.contains_key(&state.task_id) || !state.is_expired(timestamp)
+            });
+        }
+    }
+
+    /// Timestamp of the next run of the task, `None` if the task doesn't exist, is paused, quarantined or
+    /// is a `FixedDelay` task which wasn't completed yet
+    pub fn next_run(&self, task_id: TaskId) -> Option<u64> {
+        match self.tasks.get(&task_id) {
+            Some(task) if !task.is_paused() && !task.is_quarantined() => {}
+            _ => return None,
+        };
+
+        self.queue
+            .iter()
+            .filter(|it| it.task_id == task_id)
+            .map(|it| it.timestamp)
+            .min()
+    }
+
+    /// Up to `limit` earliest runs of all tasks which are going to happen between `from` and `to`
+    /// (inclusive), ordered by time. Rate limits are not taken into account.
+    pub fn upcoming(&self, from: u64, to: u64, limit: usize) -> Vec<TaskTimestamp> {
+        if limit == 0 {
+            return vec![];
+        }
+
+        let seed = self.seed;
+        // the latest of the earliest runs found so far is on top
+        let mut result = BinaryHeap::new();
+
+        for queued in self.queue.iter() {
+            let task = match self.tasks.get(&queued.task_id) {
+                Some(task) if !task.is_paused() && !task.is_quarantined() => task,
+                _ => continue,
+            };
+
+            let options = &task.scheduling_options;
+            // a manual run is queued in addition to the regular one and doesn't start a series
+            let is_manual = task.triggered_at == Some(queued.timestamp);
+            let planned_at = if is_manual {
+                queued.timestamp
+            } else {
+                queued
+                    .timestamp
+                    .saturating_sub(task.run_offset.unwrap_or_default())
+            };
+
+            // the next run of a FixedDelay task is only known once the previous one is completed
+            let runs = match options.get_mode() {
+                _ if is_manual => 1,
+                SchedulingMode::FixedRate => usize::MAX,
+                SchedulingMode::FixedDelay => 1,
+            };
+
+            for (idx, planned_at) in options.planned_runs(planned_at).take(runs).enumerate() {
+                // offsets only delay runs, so the rest of them can't get into the result
+                let latest = result.peek().map(|(timestamp, _)| *timestamp);
+                if planned_at > to || (result.len() == limit && latest < Some(planned_at)) {
+                    break;
+                }
+
+                let timestamp = match idx {
+                    0 => queued.timestamp,
+                    _ => planned_at.saturating_add(options.run_offset(seed, task.id, planned_at)),
+                };
+
+                if !is_manual && !options.is_before_end(timestamp) {
+                    break;
+                }
+
+                if timestamp < from || timestamp > to {
+                    continue;
+                }
+
+                result.push((timestamp, task.id));
+                if result.len() > limit {
+                    result.pop();
                 }
-                Entry::Vacant(_) => {}
             }
+        }
 
-            if should_remove {
-                self.tasks.remove(&task_id);
+        result
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(timestamp, task_id)| TaskTimestamp { task_id, timestamp })
+            .collect()
+    }
+
+    pub fn set_rate_limit(
+        &mut self,
+        group: String,
+        limit: RateLimit,
+        timestamp: u64,
+    ) -> CronResult<()> {
+        limit.validate()?;
+
+        self.rate_limits
+            .get_or_insert_with(HashMap::new)
+            .insert(group, TokenBucket::new(limit, timestamp));
+
+        Ok(())
+    }
+
+    pub fn remove_rate_limit(&mut self, group: &str) -> Option<RateLimit> {
+        self.rate_limits
+            .as_mut()
+            .and_then(|rate_limits| rate_limits.remove(group))
+            .map(|bucket| bucket.limit)
+    }
+
+    pub fn iterate(&mut self, timestamp: u64) -> Vec<ScheduledTask> {
+        self.iterate_limited(timestamp, usize::MAX)
+    }
+
+    /// Promotes all due tasks, so they are returned strictly by priority
+    pub fn iterate_limited(&mut self, timestamp: u64, max_tasks: usize) -> Vec<ScheduledTask> {
+        self.iterate_within_budget(timestamp, max_tasks, || true)
+    }
+
+    /// Same as `iterate_limited()`, but stops once `has_budget` returns `false` (e.g. when
+    /// `instruction_counter()` reaches a limit). It is checked before each promoted and each returned
+    /// task, so due tasks which weren't promoted in time are promoted by one of the next calls.
+    pub fn iterate_within_budget<F: FnMut() -> bool>(
+        &mut self,
+        timestamp: u64,
+        max_tasks: usize,
+        mut has_budget: F,
+    ) -> Vec<ScheduledTask> {
+        self.promote_ready_tasks_within_budget(timestamp, &mut has_budget);
+
+        let mut tasks = vec![];
+
+        while tasks.len() < max_tasks && has_budget() {
+            match self.pop_ready_task(timestamp) {
+                Some(task) => tasks.push(task),
+                None => break,
             }
         }
 
         tasks
     }
 
-    pub fn dequeue(&mut self, task_id: TaskId) -> Option<ScheduledTask> {
-        self.tasks.remove(&task_id)
+    pub fn promote_ready_tasks(&mut self, timestamp: u64) {
+        self.promote_ready_tasks_within_budget(timestamp, || true);
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.queue.is_empty()
-    }
+    /// Promotes due tasks until `has_budget` returns `false`
+    pub fn promote_ready_tasks_within_budget<F: FnMut() -> bool>(
+        &mut self,
+        timestamp: u64,
+        has_budget: F,
+    ) {
+        let tasks = &self.tasks;
+        let fair = self.fair_scheduling.is_some();
 
-    pub fn get_task(&self, task_id: &TaskId) -> Option<&ScheduledTask> {
-        self.tasks.get(task_id)
+        self.queue.promote_ready(
+            timestamp,
+            |task_id| match tasks.get(&task_id) {
+                Some(task) => (
+                    task.scheduling_options.get_priority(),
+                    match &task.namespace {
+                        Some(namespace) if fair => namespace.clone(),
+                        _ => String::new(),
+                    },
+                ),
+                None => (0, String::new()),
+            },
+            has_budget,
+        );
     }
 
-    pub fn get_task_mut(&mut self, task_id: &TaskId) -> Option<&mut ScheduledTask> {
-        self.tasks.get_mut(task_id)
+    pub fn pop_ready_task(&mut self, now: u64) -> Option<ScheduledTask> {
+        while let Some(TaskTimestamp { task_id, timestamp }) = self.pop_next_ready() {
+            let task = match self.tasks.get_mut(&task_id) {
+                Some(task) if !task.is_quarantined() => task,
+                _ => continue,
+            };
+
+            // manual runs don't affect the regular schedule
+            if task.triggered_at == Some(timestamp) {
+                task.triggered_at = None;
+
+                let mut is_final = false;
+                if self.count_manual_runs.unwrap_or_default() {
+                    if let Iterations::Exact(times_left) = task.scheduling_options.iterations {
+                        task.scheduling_options.iterations =
+                            Iterations::Exact(times_left.saturating_sub(1));
+                        is_final = times_left <= 1;
+                    }
+                }
+
+                let remaining = match task.scheduling_options.iterations {
+                    Iterations::Exact(times_left) => Some(times_left),
+                    Iterations::Infinite => None,
+                };
+
+                let task = Self::start_run(task, timestamp, remaining, is_final, true);
+                Self::record_run(
+                    &mut self.run_log,
+                    &mut self.run_counters,
+                    task_id,
+                    timestamp,
+                    now,
+                );
+
+                self.notify(&task, TaskEvent::Fired);
+
+                if is_final {
+                    // the regular run is still queued, it shouldn't outlive the task
+                    self.queue.remove(task_id);
+                    self.remove_task(task_id, TaskEvent::Completed);
+                }
+
+                return Some(task);
+            }
+
+            let planned_at = timestamp.saturating_sub(task.run_offset.unwrap_or_default());
+
+            // paused tasks are parked outside of the queue until they are resumed
+            if task.is_paused() {
+                task.paused_run_at = Some(planned_at);
+
+                continue;
+            }
+
+            let bucket = match (&task.group, self.rate_limits.as_mut()) {
+                (Some(group), Some(rate_limits)) => rate_limits.get_mut(group),
+                _ => None,
+            };
+
+            if let Some(bucket) = bucket {
+                if !bucket.try_take(now) {
+                    task.deferred_from.get_or_insert(timestamp);
+                    let timestamp = max(bucket.next_token_at(), now);
+
+                    task.run_offset = Some(timestamp.saturating_sub(planned_at));
+                    self.queue.push(TaskTimestamp { task_id, timestamp });
+
+                    continue;
+                }
+            }
+
+            // lateness includes the delay caused by the rate limit
+            Self::record_run(
+                &mut self.run_log,
+                &mut self.run_counters,
+                task_id,
+                task.deferred_from.take().unwrap_or(timestamp),
+                now,
+            );
+
+            let next_run_at = match task.scheduling_options.iterations {
+                Iterations::Infinite => Some(Self::plan_next_run(task, planned_at)),
+                Iterations::Exact(times_left) if times_left > 1 => {
+                    task.scheduling_options.iterations = Iterations::Exact(times_left - 1);
+
+                    Some(Self::plan_next_run(task, planned_at))
+                }
+                Iterations::Exact(_) => None,
+            };
+
+            let should_remove = match next_run_at {
+                Some(_) if task.scheduling_options.get_mode() == SchedulingMode::FixedDelay => {
+                    task.awaiting_completion = Some(true);
+
+                    false
+                }
+                Some(Some(timestamp)) => {
+                    !Self::push_run(&mut self.queue, self.seed, task, timestamp)
+                }
+                // the next run doesn't fit into u64
+                Some(None) => true,
+                None => true,
+            };
+
+            // only iterations are counted, a run which is final because of `end_at` may have some left
+            let remaining = match (task.scheduling_options.iterations, next_run_at) {
+                (Iterations::Infinite, _) => None,
+                (Iterations::Exact(_), None) => Some(0),
+                (Iterations::Exact(times_left), Some(_)) => Some(times_left),
+            };
+
+            let task = Self::start_run(task, planned_at, remaining, should_remove, false);
+
+            self.notify(&task, TaskEvent::Fired);
+
+            if should_remove {
+                let event = match next_run_at {
+                    Some(_) => TaskEvent::Expired,
+                    None => TaskEvent::Completed,
+                };
+
+                self.remove_task(task_id, event);
+            }
+
+            return Some(task);
+        }
+
+        None
     }
 
-    pub fn get_task_by_id_cloned(&self, task_id: &TaskId) -> Option<ScheduledTask> {
-        self.get_task(task_id).cloned()
+    /// Should be called once a task is handled, schedules the next run of `FixedDelay` tasks.
+    /// Returns `Ok(false)` if the task isn't awaiting completion.
+    pub fn complete(&mut self, task_id: TaskId, completed_at: u64) -> CronResult<bool> {
+        let task = match self.tasks.get_mut(&task_id) {
+            Some(task) if task.is_awaiting_completion() => task,
+            Some(_) => return Ok(false),
+            None => return Err(CronError::TaskNotFound(task_id)),
+        };
+
+        task.awaiting_completion = None;
+
+        let is_pushed = match task.scheduling_options.next_run_after(completed_at) {
+            Some(timestamp) => Self::push_run(&mut self.queue, self.seed, task, timestamp),
+            None => false,
+        };
+
+        if is_pushed {
+            self.notify_by_id(task_id, TaskEvent::Rescheduled);
+        } else {
+            self.remove_task(task_id, TaskEvent::Expired);
+        }
+
+        Ok(true)
     }
 
-    pub fn get_tasks_cloned(&self) -> Vec<ScheduledTask> {
-        self.tasks.values().cloned().collect()
+    pub fn report_success(&mut self, task_id: TaskId) {
+        if let Some(run_log) = self.run_log.as_mut() {
+            run_log.set_outcome(task_id, RunOutcome::Succeeded, None);
+        }
+
+        self.run_counters
+            .get_or_insert_with(RunCounters::default)
+            .succeeded += 1;
+
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            task.consecutive_failures = None;
+        }
     }
 
-    fn generate_task_id(&mut self) -> TaskId {
-        let res = self.task_id_counter;
-        self.task_id_counter += 1;
+    /// Returns `true` if the task got quarantined because of this failure
+    pub fn report_failure(&mut self, task_id: TaskId, error: String) -> bool {
+        if let Some(run_log) = self.run_log.as_mut() {
+            run_log.set_outcome(task_id, RunOutcome::Failed, Some(error.clone()));
+        }
+
+        match self.tasks.get(&task_id) {
+            Some(task) => Self::emit(
+                &mut self.event_log,
+                &self.observer,
+                task,
+                TaskEvent::Failed,
+                Some(error),
+            ),
+            None => Self::log_event(&mut self.event_log, task_id, TaskEvent::Failed, Some(error)),
+        }
+
+        self.run_counters
+            .get_or_insert_with(RunCounters::default)
+            .failed += 1;
+
+        let threshold = self
+            .quarantine_threshold
+            .unwrap_or(DEFAULT_QUARANTINE_THRESHOLD);
+
+        match self.tasks.get_mut(&task_id) {
+            Some(task) if !task.is_quarantined() => {
+                let failures = task.consecutive_failures.unwrap_or_default() + 1;
+                task.consecutive_failures = Some(failures);
+
+                if failures < threshold {
+                    return false;
+                }
+
+                task.quarantined = Some(true);
+                self.notify_by_id(task_id, TaskEvent::Quarantined);
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get_metrics(&self, now: u64) -> CronMetrics {
+        let count_tasks = |filter: fn(&ScheduledTask) -> bool| {
+            self.tasks.values().filter(|task| filter(task)).count() as u64
+        };
+
+        CronMetrics {
+            tasks: self.tasks.len() as u64,
+            paused_tasks: count_tasks(ScheduledTask::is_paused),
+            quarantined_tasks: count_tasks(ScheduledTask::is_quarantined),
+            awaiting_completion_tasks: count_tasks(ScheduledTask::is_awaiting_completion),
+            queue_length: self.queue.len() as u64,
+            backlog: self.queue.iter().filter(|it| it.timestamp <= now).count() as u64,
+            runs: self.run_counters.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Keeps up to `capacity` most recent runs in the run log, `0` disables the run log
+    pub fn set_run_log_capacity(&mut self, capacity: u64) {
+        self.run_log
+            .get_or_insert_with(|| RunLog::new(capacity))
+            .set_capacity(capacity);
+    }
+
+    /// Logged runs of the task, the most recent first
+    pub fn get_runs(&self, task_id: TaskId) -> Vec<RunLogEntry> {
+        self.get_recent_runs_filtered(usize::MAX, |it| it.task_id == task_id)
+    }
+
+    /// Up to `limit` most recent runs of all tasks, the most recent first
+    pub fn get_recent_runs(&self, limit: usize) -> Vec<RunLogEntry> {
+        self.get_recent_runs_filtered(limit, |_| true)
+    }
+
+    /// Makes the task due at `timestamp` once, in addition to its regular runs, which keep their cadence
+    pub fn trigger_now(&mut self, task_id: TaskId, timestamp: u64) -> CronResult<()> {
+        let task = self
+            .tasks
+            .get_mut(&task_id)
+            .ok_or(CronError::TaskNotFound(task_id))?;
+
+        if task.triggered_at.is_none() {
+            task.triggered_at = Some(timestamp);
+            self.queue.push(TaskTimestamp { task_id, timestamp });
+            self.notify_by_id(task_id, TaskEvent::Triggered);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `Ok(false)` if the task isn't quarantined
+    pub fn release_quarantine(&mut self, task_id: TaskId, timestamp: u64) -> CronResult<bool> {
+        match self.tasks.get_mut(&task_id) {
+            Some(task) if task.is_quarantined() => {
+                task.quarantined = None;
+                task.consecutive_failures = None;
+                task.run_offset = None;
+                task.deferred_from = None;
+                task.triggered_at = None;
+                task.paused_run_at = None;
+
+                self.queue.remove(task_id);
+                self.notify_by_id(task_id, TaskEvent::Released);
+
+                let task = self.tasks.get_mut(&task_id).unwrap();
+                if !Self::push_run(&mut self.queue, self.seed, task, timestamp) {
+                    self.remove_task(task_id, TaskEvent::Expired);
+                }
+
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(CronError::TaskNotFound(task_id)),
+        }
+    }
+
+    /// Unlike other functions, which accept a task id, returns `None` rather than `Err(TaskNotFound)`
+    /// if there is no such task, to stay compatible with earlier versions
+    pub fn dequeue(&mut self, task_id: TaskId) -> Option<ScheduledTask> {
+        let task = self.remove_task(task_id, TaskEvent::Cancelled)?;
+        // queued runs of cancelled tasks shouldn't be counted in metrics
+        self.queue.remove(task_id);
+
+        Some(task)
+    }
+
+    /// Up to `limit` (capped at `MAX_EVENTS_PAGE_SIZE`) events with sequence numbers starting from
+    /// `from_seq`. If the returned events start with a greater sequence number, older events were
+    /// already pruned.
+    pub fn get_events(&self, from_seq: u64, limit: usize) -> Vec<CronEvent> {
+        self.event_log
+            .as_ref()
+            .map(|event_log| event_log.get(from_seq, limit.min(MAX_EVENTS_PAGE_SIZE)))
+            .unwrap_or_default()
+    }
+
+    /// Enables the event log, keeping up to `max_events` most recent events. The log is disabled
+    /// until this is called, `0` disables it again.
+    pub fn set_event_log_retention(&mut self, max_events: u64) {
+        self.event_log
+            .get_or_insert_with(|| EventLog::new(max_events))
+            .set_max_events(max_events);
+    }
+
+    /// Removes events with sequence numbers less than `seq`, e.g. once they are consumed
+    pub fn prune_events(&mut self, seq: u64) {
+        if let Some(event_log) = self.event_log.as_mut() {
+            event_log.prune_before(seq);
+        }
+    }
+
+    /// Registers an observer, which is notified about lifecycle events of all tasks, replacing the
+    /// previous one. Observers are not persisted, so they should be registered again after an upgrade.
+    pub fn set_observer<O: CronObserver + 'static>(&mut self, observer: O) {
+        self.observer = ObserverSlot(Some(Rc::new(observer)));
+    }
+
+    pub fn remove_observer(&mut self) {
+        self.observer = ObserverSlot::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn get_task(&self, task_id: &TaskId) -> Option<&ScheduledTask> {
+        self.tasks.get(task_id)
+    }
+
+    pub fn get_task_mut(&mut self, task_id: &TaskId) -> Option<&mut ScheduledTask> {
+        self.tasks.get_mut(task_id)
+    }
+
+    pub fn get_task_by_id_cloned(&self, task_id: &TaskId) -> Option<ScheduledTask> {
+        self.get_task(task_id).cloned()
+    }
+
+    pub fn get_tasks_cloned(&self) -> Vec<ScheduledTask> {
+        self.tasks.values().cloned().collect()
+    }
+
+    /// Up to `limit` tasks with ids greater than `start_after`, ordered by id
+    pub fn list_tasks(&self, start_after: Option<TaskId>, limit: usize) -> Vec<ScheduledTask> {
+        self.query_tasks(&TaskQuery {
+            cursor: start_after.map(|task_id| TaskCursor {
+                key: task_id,
+                task_id,
+            }),
+            limit: limit as u64,
+            ..Default::default()
+        })
+        .tasks
+    }
+
+    /// Returns a page of tasks matching the filter, pass `next_cursor` to the next query to get the next page.
+    /// Each page scans all tasks (or all tasks with the tag or the owner, if the filter has one), so it
+    /// costs `O(n + limit * log(limit))`.
+    pub fn query_tasks(&self, query: &TaskQuery) -> TaskPage {
+        let mut next_runs: HashMap<TaskId, u64> = HashMap::new();
+        for it in self.queue.iter() {
+            let next_run = next_runs.entry(it.task_id).or_insert(it.timestamp);
+            *next_run = (*next_run).min(it.timestamp);
+        }
+
+        let filter = &query.filter;
+        let candidates = match (&filter.tag, &filter.owner) {
+            (Some(tag), _) => self.get_task_ids_by_tag(tag),
+            (None, Some(owner)) => self.get_task_ids_by_owner(owner),
+            (None, None) => self.tasks.keys().copied().collect(),
+        };
+
+        let mut keys: Vec<_> = candidates
+            .into_iter()
+            .filter_map(|task_id| self.tasks.get(&task_id))
+            .filter(|task| {
+                let next_run = next_runs.get(&task.id);
+
+                filter.state.map_or(true, |state| task.get_state() == state)
+                    && filter
+                        .tag
+                        .as_ref()
+                        .map_or(true, |tag| task.tags.iter().flatten().any(|it| it == tag))
+                    && filter.owner.map_or(true, |owner| task.owner == Some(owner))
+                    && filter.due_before.map_or(true, |due_before| {
+                        next_run.is_some_and(|next_run| *next_run < due_before)
+                    })
+            })
+            .map(|task| {
+                let key = match query.order {
+                    TaskOrder::Id => task.id,
+                    TaskOrder::NextRun => next_runs.get(&task.id).copied().unwrap_or(u64::MAX),
+                };
+
+                (key, task.id)
+            })
+            .filter(|it| {
+                query
+                    .cursor
+                    .map_or(true, |cursor| *it > (cursor.key, cursor.task_id))
+            })
+            .collect();
+
+        let limit = match query.limit {
+            0 => DEFAULT_PAGE_SIZE,
+            limit => limit.min(MAX_PAGE_SIZE),
+        } as usize;
+
+        let has_more = keys.len() > limit;
+        if has_more {
+            keys.select_nth_unstable(limit - 1);
+            keys.truncate(limit);
+        }
+        keys.sort_unstable();
+
+        let next_cursor = match keys.last() {
+            Some((key, task_id)) if has_more => Some(TaskCursor {
+                key: *key,
+                task_id: *task_id,
+            }),
+            _ => None,
+        };
+
+        let tasks = keys
+            .into_iter()
+            .map(|(_, task_id)| {
+                let mut task = self.tasks[&task_id].clone();
+
+                if query.omit_payload {
+                    task.payload.data.clear();
+                }
+
+                task
+            })
+            .collect();
+
+        TaskPage { tasks, next_cursor }
+    }
+
+    pub fn get_task_ids_by_tag(&self, tag: &str) -> Vec<TaskId> {
+        Self::lookup(&self.tag_index, tag)
+    }
+
+    pub fn get_task_ids_by_group(&self, group: &str) -> Vec<TaskId> {
+        Self::lookup(&self.group_index, group)
+    }
+
+    pub fn get_tasks_by_tag_cloned(&self, tag: &str) -> Vec<ScheduledTask> {
+        self.get_task_ids_by_tag(tag)
+            .iter()
+            .filter_map(|task_id| self.get_task_by_id_cloned(task_id))
+            .collect()
+    }
+
+    pub fn dequeue_by_tag(&mut self, tag: &str) -> Vec<ScheduledTask> {
+        self.get_task_ids_by_tag(tag)
+            .into_iter()
+            .filter_map(|task_id| self.dequeue(task_id))
+            .collect()
+    }
+
+    /// Returns `Ok(false)` if the task is already paused
+    pub fn pause(&mut self, task_id: TaskId) -> CronResult<bool> {
+        if !self.set_paused(task_id, true)? {
+            return Ok(false);
+        }
+
+        self.notify_by_id(task_id, TaskEvent::Paused);
+
+        Ok(true)
+    }
+
+    /// A resumed task keeps its cadence, runs which were planned before `timestamp` are skipped.
+    /// Returns `Ok(false)` if the task isn't paused.
+    pub fn resume(&mut self, task_id: TaskId, timestamp: u64) -> CronResult<bool> {
+        if !self.set_paused(task_id, false)? {
+            return Ok(false);
+        }
+
+        self.notify_by_id(task_id, TaskEvent::Resumed);
+        let task = self.tasks.get_mut(&task_id).unwrap();
+
+        if let Some(planned_at) = task.paused_run_at.take() {
+            let run_at = task
+                .scheduling_options
+                .next_run_since(planned_at, timestamp);
+
+            if !Self::push_run(&mut self.queue, self.seed, task, run_at) {
+                self.remove_task(task_id, TaskEvent::Expired);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns how many tasks were paused
+    pub fn pause_by_tag(&mut self, tag: &str) -> usize {
+        self.get_task_ids_by_tag(tag)
+            .into_iter()
+            .filter(|task_id| self.pause(*task_id).unwrap_or_default())
+            .count()
+    }
+
+    /// Returns how many tasks were resumed
+    pub fn resume_by_tag(&mut self, tag: &str, timestamp: u64) -> usize {
+        self.get_task_ids_by_tag(tag)
+            .into_iter()
+            .filter(|task_id| self.resume(*task_id, timestamp).unwrap_or_default())
+            .count()
+    }
+
+    pub fn get_task_ids_by_owner(&self, owner: &Principal) -> Vec<TaskId> {
+        Self::lookup(&self.owner_index, owner)
+    }
+
+    pub fn set_quota(&mut self, owner: Principal, quota: TaskQuota) {
+        self.quotas
+            .get_or_insert_with(HashMap::new)
+            .insert(owner, quota);
+    }
+
+    pub fn remove_quota(&mut self, owner: &Principal) -> Option<TaskQuota> {
+        self.quotas.as_mut().and_then(|quotas| quotas.remove(owner))
+    }
+
+    /// Checks whether `owner` can have `new_tasks` more active tasks scheduled with `scheduling_options`
+    pub fn check_quota(
+        &self,
+        owner: &Principal,
+        scheduling_options: &SchedulingOptions,
+        new_tasks: u64,
+    ) -> CronResult<()> {
+        let quota = match self
+            .quotas
+            .as_ref()
+            .and_then(|quotas| quotas.get(owner))
+            .or(self.default_quota.as_ref())
+        {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        if let Some(max_active_tasks) = quota.max_active_tasks {
+            let active_tasks = self
+                .owner_index
+                .as_ref()
+                .and_then(|index| index.get(owner))
+                .map_or(0, |ids| ids.len() as u64);
+
+            if active_tasks + new_tasks > max_active_tasks {
+                return Err(CronError::QuotaExceeded(format!(
+                    "Principal {} can't have more than {} active tasks",
+                    owner, max_active_tasks
+                )));
+            }
+        }
+
+        if let Some(min_interval_nano) = quota.min_interval_nano {
+            if scheduling_options.is_recurring()
+                && scheduling_options.interval_nano < min_interval_nano
+            {
+                return Err(CronError::QuotaExceeded(format!(
+                    "Principal {} can't schedule tasks with interval less than {} ns",
+                    owner, min_interval_nano
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_owner(&self, task_id: TaskId, caller: &Principal) -> CronResult<()> {
+        match self.tasks.get(&task_id) {
+            Some(task) if task.owner.as_ref() == Some(caller) => Ok(()),
+            Some(_) => Err(CronError::NotOwner {
+                task_id,
+                caller: *caller,
+            }),
+            None => Err(CronError::TaskNotFound(task_id)),
+        }
+    }
+
+    pub fn dequeue_owned(
+        &mut self,
+        task_id: TaskId,
+        caller: &Principal,
+    ) -> CronResult<ScheduledTask> {
+        self.check_owner(task_id, caller)?;
+
+        Ok(self.dequeue(task_id).unwrap())
+    }
+
+    pub fn reschedule_owned(
+        &mut self,
+        task_id: TaskId,
+        scheduling_options: SchedulingOptions,
+        iterations_policy: IterationsPolicy,
+        caller: &Principal,
+        timestamp: u64,
+    ) -> CronResult<()> {
+        self.check_owner(task_id, caller)?;
+
+        self.reschedule(task_id, scheduling_options, iterations_policy, timestamp)
+    }
+
+    pub fn update_payload_owned<TaskPayload: CandidType>(
+        &mut self,
+        task_id: TaskId,
+        payload: TaskPayload,
+        caller: &Principal,
+    ) -> CronResult<()> {
+        self.check_owner(task_id, caller)?;
+
+        self.update_payload(task_id, payload)
+    }
+
+    fn get_recent_runs_filtered<F: Fn(&RunLogEntry) -> bool>(
+        &self,
+        limit: usize,
+        filter: F,
+    ) -> Vec<RunLogEntry> {
+        self.run_log
+            .iter()
+            .flat_map(|run_log| run_log.iter().rev())
+            .filter(|it| filter(it))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Counts the run and returns a copy of the task describing it
+    fn start_run(
+        task: &mut ScheduledTask,
+        planned_at: u64,
+        remaining: Option<u64>,
+        is_final: bool,
+        is_manual: bool,
+    ) -> ScheduledTask {
+        let index = task.get_run_count();
+        task.run_count = Some(index + 1);
+
+        ScheduledTask {
+            current_run: Some(RunInfo {
+                index,
+                planned_at,
+                remaining,
+                is_final,
+                is_manual,
+            }),
+            ..task.clone()
+        }
+    }
+
+    fn record_run(
+        run_log: &mut Option<RunLog>,
+        run_counters: &mut Option<RunCounters>,
+        task_id: TaskId,
+        planned_at: u64,
+        now: u64,
+    ) {
+        let lateness_nano = now.saturating_sub(planned_at);
+
+        run_log
+            .get_or_insert_with(|| RunLog::new(DEFAULT_RUN_LOG_CAPACITY))
+            .push(RunLogEntry {
+                task_id,
+                planned_at,
+                executed_at: now,
+                lateness_nano,
+                outcome: RunOutcome::Pending,
+                error: None,
+            });
+
+        let counters = run_counters.get_or_insert_with(RunCounters::default);
+        counters.executed += 1;
+        counters.lateness_nano.observe(lateness_nano);
+    }
+
+    fn set_paused(&mut self, task_id: TaskId, paused: bool) -> CronResult<bool> {
+        match self.tasks.get_mut(&task_id) {
+            Some(task) if task.is_paused() != paused => {
+                task.paused = if paused { Some(true) } else { None };
+
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(CronError::TaskNotFound(task_id)),
+        }
+    }
+
+    fn lookup<K, Q>(index: &Option<HashMap<K, BTreeSet<TaskId>>>, key: &Q) -> Vec<TaskId>
+    where
+        K: Borrow<Q> + Eq + Hash,
+        Q: Eq + Hash + ?Sized,
+    {
+        index
+            .as_ref()
+            .and_then(|index| index.get(key))
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn plan_next_run(task: &mut ScheduledTask, planned_at: u64) -> Option<u64> {
+        task.delay_passed = true;
+        task.rescheduled_at = Some(planned_at);
+
+        task.scheduling_options.next_run_after(planned_at)
+    }
+
+    pub fn set_seed(&mut self, random_bytes: &[u8]) {
+        let mut seed = [0u8; 8];
+        let len = random_bytes.len().min(seed.len());
+        seed[..len].copy_from_slice(&random_bytes[..len]);
+
+        self.seed = Some(u64::from_le_bytes(seed));
+    }
+
+    fn notify(&mut self, task: &ScheduledTask, event: TaskEvent) {
+        Self::emit(&mut self.event_log, &self.observer, task, event, None);
+    }
+
+    fn notify_by_id(&mut self, task_id: TaskId, event: TaskEvent) {
+        if let Some(task) = self.tasks.get(&task_id) {
+            Self::emit(&mut self.event_log, &self.observer, task, event, None);
+        }
+    }
+
+    fn emit(
+        event_log: &mut Option<EventLog>,
+        observer: &ObserverSlot,
+        task: &ScheduledTask,
+        event: TaskEvent,
+        error: Option<String>,
+    ) {
+        Self::log_event(event_log, task.id, event, error);
+
+        if let Some(observer) = &observer.0 {
+            observer.on_event(task, event);
+        }
+    }
+
+    fn log_event(
+        event_log: &mut Option<EventLog>,
+        task_id: TaskId,
+        event: TaskEvent,
+        error: Option<String>,
+    ) {
+        if let Some(event_log) = event_log.as_mut() {
+            event_log.push(task_id, event, error);
+        }
+    }
+
+    fn remove_task(&mut self, task_id: TaskId, event: TaskEvent) -> Option<ScheduledTask> {
+        let task = self.tasks.remove(&task_id)?;
+
+        self.unindex_task(&task);
+
+        if let (Some(key), Some(debounced)) = (&task.debounce_key, self.debounced.as_mut()) {
+            debounced.remove(key);
+        }
+
+        if let (Some(key), Some(keys)) = (&task.idempotency_key, self.idempotency_keys.as_mut()) {
+            keys.remove(key);
+        }
+
+        self.notify(&task, event);
+
+        Some(task)
+    }
+
+    fn pop_next_ready(&mut self) -> Option<TaskTimestamp> {
+        let fair_scheduling = &self.fair_scheduling;
+
+        self.queue.pop_next(|namespace| {
+            fair_scheduling
+                .as_ref()
+                .map_or(1, |it| it.weight_of(namespace))
+        })
+    }
+
+    fn index_task(&mut self, task: &ScheduledTask) {
+        if let Some(group) = &task.group {
+            self.group_index
+                .get_or_insert_with(HashMap::new)
+                .entry(group.clone())
+                .or_default()
+                .insert(task.id);
+        }
+
+        for tag in task.tags.iter().flatten() {
+            self.tag_index
+                .get_or_insert_with(HashMap::new)
+                .entry(tag.clone())
+                .or_default()
+                .insert(task.id);
+        }
+
+        if let Some(owner) = task.owner {
+            self.owner_index
+                .get_or_insert_with(HashMap::new)
+                .entry(owner)
+                .or_default()
+                .insert(task.id);
+        }
+    }
+
+    fn unindex_task(&mut self, task: &ScheduledTask) {
+        if let (Some(group), Some(index)) = (&task.group, self.group_index.as_mut()) {
+            Self::unindex(index, group, task.id);
+        }
+
+        if let (Some(tags), Some(index)) = (&task.tags, self.tag_index.as_mut()) {
+            for tag in tags {
+                Self::unindex(index, tag, task.id);
+            }
+        }
+
+        if let (Some(owner), Some(index)) = (&task.owner, self.owner_index.as_mut()) {
+            Self::unindex(index, owner, task.id);
+        }
+    }
+
+    fn unindex<K: Eq + Hash>(index: &mut HashMap<K, BTreeSet<TaskId>>, key: &K, task_id: TaskId) {
+        if let Some(ids) = index.get_mut(key) {
+            ids.remove(&task_id);
+
+            if ids.is_empty() {
+                index.remove(key);
+            }
+        }
+    }
+
+    fn schedule_first_run(
+        queue: &mut TaskExecutionQueue,
+        seed: Option<u64>,
+        task: &mut ScheduledTask,
+        timestamp: u64,
+    ) {
+        task.scheduled_at = timestamp;
+        task.rescheduled_at = None;
+        task.delay_passed = false;
+        task.awaiting_completion = None;
+        task.triggered_at = None;
+
+        let first_run_at = task.scheduling_options.first_run_at(timestamp);
+
+        let has_runs = match task.scheduling_options.iterations {
+            Iterations::Exact(times) => times > 0,
+            Iterations::Infinite => true,
+        };
+
+        if has_runs {
+            Self::push_run(queue, seed, task, first_run_at);
+        }
+    }
+
+    /// Returns `false` if the run (including jitter and spread) would happen after `end_at`
+    fn push_run(
+        queue: &mut TaskExecutionQueue,
+        seed: Option<u64>,
+        task: &mut ScheduledTask,
+        planned_at: u64,
+    ) -> bool {
+        let offset = task
+            .scheduling_options
+            .run_offset(seed, task.id, planned_at);
+        let timestamp = planned_at.saturating_add(offset);
+
+        if !task.scheduling_options.is_before_end(timestamp) {
+            return false;
+        }
+
+        task.run_offset = if offset > 0 { Some(offset) } else { None };
+        task.deferred_from = None;
+        task.paused_run_at = None;
+        queue.push(TaskTimestamp {
+            task_id: task.id,
+            timestamp,
+        });
+
+        true
+    }
+
+    fn generate_task_id(&mut self) -> TaskId {
+        let res = self.task_id_counter;
+        self.task_id_counter += 1;
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use ic_cdk::export::candid::{decode_one, encode_one, Principal};
+    use ic_cdk::export::candid::{CandidType, Deserialize};
+
+    use crate::task_scheduler::{
+        TaskScheduler, DEFAULT_PAGE_SIZE, DEFAULT_QUARANTINE_THRESHOLD, MAX_EVENTS_PAGE_SIZE,
+        MAX_PAGE_SIZE,
+    };
+    use crate::types::{
+        Alignment, CronError, CronObserver, EnqueueOptions, FairScheduling, Iterations,
+        IterationsPolicy, RateLimit, RunInfo, RunOutcome, ScheduledTask, SchedulingMode,
+        SchedulingOptions, TaskEvent, TaskFilter, TaskId, TaskOrder, TaskQuery, TaskQuota,
+        TaskState, NANOS_IN_DAY, NANOS_IN_HOUR,
+    };
+
+    #[derive(CandidType, Deserialize)]
+    pub struct TestPayload {
+        pub a: bool,
+    }
+
+    #[test]
+    fn main_flow_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id_1 = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Exact(1),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let task_id_2 = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let task_id_3 = scheduler
+            .enqueue(
+                TestPayload { a: false },
+                SchedulingOptions {
+                    delay_nano: 20,
+                    interval_nano: 20,
+                    iterations: Iterations::Exact(2),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        assert!(!scheduler.is_empty(), "Scheduler is not empty");
+
+        let tasks_emp = scheduler.iterate(5);
+        assert!(
+            tasks_emp.is_empty(),
+            "There should not be any tasks at timestamp 5"
+        );
+
+        let tasks_1_2 = scheduler.iterate(10);
+        assert_eq!(
+            tasks_1_2.len(),
+            2,
+            "At timestamp 10 there should be 2 tasks"
+        );
+        assert!(
+            tasks_1_2.iter().any(|t| t.id == task_id_1),
+            "Should contain task 1"
+        );
+        assert!(
+            tasks_1_2.iter().any(|t| t.id == task_id_2),
+            "Should contain task 2"
+        );
+
+        let tasks_emp = scheduler.iterate(15);
+        assert!(
+            tasks_emp.is_empty(),
+            "There should not be any tasks at timestamp 15"
+        );
+
+        let tasks_2_3 = scheduler.iterate(20);
+        assert_eq!(
+            tasks_2_3.len(),
+            2,
+            "At timestamp 20 there should be 2 tasks"
+        );
+        assert!(
+            tasks_2_3.iter().any(|t| t.id == task_id_2),
+            "Should contain task 2"
+        );
+        assert!(
+            tasks_2_3.iter().any(|t| t.id == task_id_3),
+            "Should contain task 3"
+        );
+
+        let tasks_2 = scheduler.iterate(30);
+        assert_eq!(
+            tasks_2.len(),
+            1,
+            "There should be a single task at timestamp 30"
+        );
+        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
+
+        let tasks_2_3 = scheduler.iterate(42);
+        assert_eq!(
+            tasks_2_3.len(),
+            2,
+            "At timestamp 40 there should be 2 tasks"
+        );
+        assert!(
+            tasks_2_3.iter().any(|t| t.id == task_id_2),
+            "Should contain task 2"
+        );
+        assert!(
+            tasks_2_3.iter().any(|t| t.id == task_id_3),
+            "Should contain task 3"
+        );
+
+        let tasks_2 = scheduler.iterate(55);
+        assert_eq!(
+            tasks_2.len(),
+            1,
+            "There should be a single task at timestamp 60"
+        );
+        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
+
+        let tasks_2 = scheduler.iterate(60);
+        assert_eq!(
+            tasks_2.len(),
+            1,
+            "There should be a single task at timestamp 60"
+        );
+        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
+
+        scheduler.dequeue(task_id_2).unwrap();
+
+        scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Exact(1),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+    }
+
+    #[test]
+    fn delay_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let _task_id_1 = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 20,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let tasks = scheduler.iterate(5);
+
+        assert!(
+            tasks.is_empty(),
+            "There shouldn't be any task at this timestamp (5)"
+        );
+
+        let tasks = scheduler.iterate(10);
+        assert_eq!(
+            tasks.len(),
+            1,
+            "There should be a task that was triggered by a delay at this timestamp (10)"
+        );
+
+        let tasks = scheduler.iterate(20);
+        assert!(
+            tasks.is_empty(),
+            "There shouldn't be any task at this timestamp (20)"
+        );
+
+        let tasks = scheduler.iterate(30);
+        assert_eq!(
+            tasks.len(),
+            1,
+            "There should be a task that was triggered by an interval at this timestamp (30)"
+        );
+
+        let tasks = scheduler.iterate(50);
+        assert_eq!(
+            tasks.len(),
+            1,
+            "There should be a task that was triggered by an interval at this timestamp (50)"
+        );
+    }
+
+    #[test]
+    fn ser_de_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 20,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let bytes = encode_one(scheduler).expect("Should be able to encode task scheduler");
+        let mut scheduler: TaskScheduler =
+            decode_one(&bytes).expect("Should be able to decode task scheduler");
+
+        let tasks = scheduler.iterate(10);
+
+        assert_eq!(
+            tasks.len(),
+            1,
+            "There should be a task that was triggered by a delay at this timestamp (10)"
+        );
+    }
+
+    #[test]
+    fn priority_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let low = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    priority: Some(1),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let high = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    priority: Some(10),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let default = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 5,
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let tasks = scheduler.iterate(10);
+        let ids: Vec<_> = tasks.iter().map(|t| t.id).collect();
+
+        assert_eq!(
+            ids,
+            vec![high, low, default],
+            "Tasks should be ordered by priority"
+        );
+    }
+
+    #[test]
+    fn limited_iteration_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let mut ids = vec![];
+        for priority in 0..5 {
+            let id = scheduler
+                .enqueue(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        delay_nano: 10,
+                        priority: Some(priority),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .ok()
+                .unwrap();
+
+            ids.push(id);
+        }
+
+        let tasks = scheduler.iterate_limited(10, 2);
+        assert_eq!(
+            tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![ids[4], ids[3]],
+            "Only two tasks with the highest priority should be returned"
+        );
+
+        let bytes = encode_one(scheduler).expect("Should be able to encode task scheduler");
+        let mut scheduler: TaskScheduler =
+            decode_one(&bytes).expect("Should be able to decode task scheduler");
+
+        let tasks = scheduler.iterate_limited(11, 2);
+        assert_eq!(
+            tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![ids[2], ids[1]],
+            "Deferred tasks should be returned on the next tick"
+        );
+
+        let tasks = scheduler.iterate(12);
+        assert_eq!(tasks.len(), 1, "The last deferred task should be returned");
+        assert_eq!(tasks[0].id, ids[0]);
+        assert!(scheduler.is_empty(), "Scheduler should be empty");
+    }
+
+    #[test]
+    fn budgeted_iteration_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let mut ids = vec![];
+        for priority in 0..5 {
+            let id = scheduler
+                .enqueue(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        delay_nano: 10,
+                        priority: Some(priority),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .unwrap();
+
+            ids.push(id);
+        }
+
+        let budget = |mut checks: u32| {
+            move || {
+                checks = checks.saturating_sub(1);
+                checks > 0
+            }
+        };
+
+        assert!(
+            scheduler.iterate_within_budget(10, 5, budget(4)).is_empty(),
+            "The budget should be checked while promoting due tasks"
+        );
+        assert_eq!(scheduler.queue.ready_len(), 3);
+
+        let tasks = scheduler.iterate_within_budget(10, 5, budget(5));
+        assert_eq!(
+            tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![ids[4], ids[3]],
+            "The rest of due tasks should be promoted by the next call"
+        );
+
+        let tasks = scheduler.iterate_within_budget(10, 5, || true);
+        assert_eq!(
+            tasks.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![ids[2], ids[1], ids[0]]
+        );
+    }
+
+    #[test]
+    fn quarantine_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        for timestamp in [10, 20] {
+            assert_eq!(scheduler.iterate(timestamp).len(), 1);
+            assert!(
+                !scheduler.report_failure(task_id, String::from("Oops")),
+                "Task shouldn't be quarantined before the threshold"
+            );
+        }
+
+        assert_eq!(scheduler.iterate(30).len(), 1);
+        scheduler.report_success(task_id);
+        assert_eq!(scheduler.iterate(40).len(), 1);
+        assert!(
+            !scheduler.report_failure(task_id, String::from("Oops")),
+            "A success should reset consecutive failures"
+        );
+
+        for timestamp in [50, 60] {
+            assert_eq!(scheduler.iterate(timestamp).len(), 1);
+            scheduler.report_failure(task_id, String::from("Oops"));
+        }
+
+        assert!(scheduler.get_task(&task_id).unwrap().is_quarantined());
+        assert!(
+            scheduler.iterate(100).is_empty(),
+            "Quarantined task should not be executed"
+        );
+
+        assert!(scheduler.release_quarantine(task_id, 105).unwrap());
+        assert_eq!(
+            scheduler.iterate(105).len(),
+            1,
+            "Released task should be executed right away"
+        );
+        assert!(scheduler.iterate(110).is_empty());
+        assert_eq!(
+            scheduler.iterate(115).len(),
+            1,
+            "Released task should continue its cadence"
+        );
+
+        let mut scheduler = TaskScheduler::default();
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    end_at: Some(50),
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+
+        for timestamp in [10, 20, 30] {
+            assert_eq!(scheduler.iterate(timestamp).len(), 1);
+            scheduler.report_failure(task_id, String::from("Oops"));
+        }
+
+        assert!(scheduler.get_task(&task_id).unwrap().is_quarantined());
+        assert!(scheduler.release_quarantine(task_id, 100).unwrap());
+        assert!(
+            scheduler.get_task(&task_id).is_none(),
+            "Released task should expire if its run is past the end"
+        );
+        assert!(scheduler.iterate(100).is_empty());
+    }
+
+    #[test]
+    fn start_and_end_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    start_at: Some(100),
+                    end_at: Some(125),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        assert!(
+            scheduler.iterate(99).is_empty(),
+            "Task should not be executed before its start"
+        );
+
+        for timestamp in [100, 110, 120] {
+            let tasks = scheduler.iterate(timestamp);
+            assert_eq!(
+                tasks.len(),
+                1,
+                "Task should be executed at timestamp {}",
+                timestamp
+            );
+        }
+
+        assert!(
+            scheduler.get_task(&task_id).is_none(),
+            "Task should be removed once its next run is past the end"
+        );
+        assert!(scheduler.is_empty(), "Scheduler should be empty");
+
+        let options = SchedulingOptions {
+            interval_nano: 10,
+            iterations: Iterations::Infinite,
+            start_at: Some(100),
+            end_at: Some(125),
+            jitter_nano: Some(20),
+            ..Default::default()
+        };
+
+        for seed in 0..20u64 {
+            let mut scheduler = TaskScheduler::default();
+            scheduler.set_seed(&seed.to_le_bytes());
+            scheduler
+                .enqueue(TestPayload { a: true }, options, 0)
+                .unwrap();
+
+            for timestamp in 0..200 {
+                scheduler.iterate(timestamp);
+            }
+
+            assert!(
+                scheduler
+                    .get_runs(0)
+                    .iter()
+                    .all(|run| run.planned_at <= 125),
+                "Jittered runs should not happen after the end"
+            );
+            assert!(scheduler.tasks.is_empty());
+        }
+
+        assert!(
+            scheduler
+                .enqueue(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        jitter_nano: Some(30),
+                        ..options
+                    },
+                    0
+                )
+                .is_err(),
+            "The first run should not be able to happen after the end"
+        );
+    }
+
+    #[test]
+    fn alignment_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano: 100,
+                    iterations: Iterations::Infinite,
+                    alignment: Some(Alignment {
+                        period_nano: 100,
+                        offset_nano: 5,
+                    }),
+                    ..Default::default()
+                },
+                37,
+            )
+            .ok()
+            .unwrap();
+
+        assert!(
+            scheduler.iterate(104).is_empty(),
+            "Task should wait for the first boundary"
+        );
+        assert_eq!(scheduler.iterate(130).len(), 1);
+        assert!(scheduler.iterate(204).is_empty());
+        assert_eq!(scheduler.iterate(205).len(), 1);
+
+        assert_eq!(
+            Alignment::hour().align(NANOS_IN_HOUR * 3),
+            Some(NANOS_IN_HOUR * 3)
+        );
+        assert_eq!(
+            Alignment::hour().align(NANOS_IN_HOUR * 3 + 1),
+            Some(NANOS_IN_HOUR * 4)
+        );
+        assert_eq!(Alignment::day().align(NANOS_IN_HOUR), Some(NANOS_IN_DAY));
+        assert_eq!(Alignment::day().align(u64::MAX), None);
+
+        let century = NANOS_IN_DAY * 365 * 100;
+        let options = SchedulingOptions {
+            interval_nano: century,
+            iterations: Iterations::Infinite,
+            alignment: Some(Alignment::day()),
+            ..Default::default()
+        };
+        let runs = (0..=u64::MAX / century)
+            .map(|it| it * century)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            options.preview(0, 100),
+            runs,
+            "Runs which don't fit into u64 should not be planned"
+        );
+
+        let mut scheduler = TaskScheduler::default();
+        let task_id = scheduler
+            .enqueue(TestPayload { a: true }, options, 0)
+            .unwrap();
+
+        assert_eq!(
+            scheduler
+                .upcoming(0, u64::MAX, 100)
+                .iter()
+                .map(|it| it.timestamp)
+                .collect::<Vec<_>>(),
+            runs
+        );
+        for timestamp in runs {
+            assert_eq!(scheduler.iterate(timestamp).len(), 1);
+        }
+        assert!(
+            scheduler.get_task(&task_id).is_none(),
+            "Task should expire once its next run doesn't fit into u64"
+        );
+    }
+
+    #[test]
+    fn fixed_delay_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Exact(3),
+                    mode: Some(SchedulingMode::FixedDelay),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        assert_eq!(scheduler.iterate(10).len(), 1);
+        assert!(
+            scheduler.iterate(20).is_empty(),
+            "Task should wait for completion"
+        );
+        assert_eq!(scheduler.get_metrics(20).awaiting_completion_tasks, 1);
+
+        assert!(scheduler.complete(task_id, 25).unwrap());
+        assert_eq!(scheduler.get_metrics(25).awaiting_completion_tasks, 0);
+        assert!(
+            !scheduler.complete(task_id, 25).unwrap(),
+            "Task can only be completed once per run"
+        );
+
+        assert!(scheduler.iterate(34).is_empty());
+        assert_eq!(
+            scheduler.iterate(35).len(),
+            1,
+            "Next run should be relative to the completion"
+        );
+
+        assert!(scheduler.complete(task_id, 50).unwrap());
+        assert_eq!(scheduler.iterate(60).len(), 1);
+        assert!(
+            scheduler.get_task(&task_id).is_none(),
+            "Task should be removed after its last run"
+        );
+    }
+
+    #[test]
+    fn jitter_and_spread_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+        scheduler.set_seed(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        for _ in 0..100 {
+            scheduler
+                .enqueue(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        interval_nano: 100,
+                        iterations: Iterations::Infinite,
+                        spread: Some(true),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .ok()
+                .unwrap();
+        }
+
+        let fired_at_once = scheduler.iterate(0).len();
+        assert!(
+            fired_at_once < 10,
+            "Spread tasks should not fire at once ({})",
+            fired_at_once
+        );
+
+        let mut fired = fired_at_once;
+        for timestamp in 1..100 {
+            fired += scheduler.iterate(timestamp).len();
+        }
+        assert_eq!(fired, 100, "Each task should fire once per interval");
+
+        let mut scheduler = TaskScheduler::default();
+        scheduler.set_seed(&[8, 7, 6, 5, 4, 3, 2, 1]);
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano: 100,
+                    iterations: Iterations::Infinite,
+                    jitter_nano: Some(10),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let mut offsets = vec![];
+        for planned_at in (0..1000).step_by(100) {
+            offsets.push(
+                scheduler
+                    .get_task(&task_id)
+                    .unwrap()
+                    .run_offset
+                    .unwrap_or_default(),
+            );
+
+            assert_eq!(scheduler.iterate(planned_at + 10).len(), 1);
+            assert_eq!(
+                scheduler.get_task(&task_id).unwrap().rescheduled_at,
+                Some(planned_at),
+                "Jitter should not shift the cadence"
+            );
+        }
+
+        assert!(offsets.iter().all(|it| *it <= 10));
+        assert!(offsets.iter().any(|it| *it > 0));
+
+        let mut scheduler = TaskScheduler::default();
+        for _ in 0..10 {
+            scheduler
+                .enqueue(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        interval_nano: 100,
+                        iterations: Iterations::Infinite,
+                        jitter_nano: Some(10),
+                        spread: Some(true),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            scheduler.iterate(0).len(),
+            10,
+            "Jitter and spread should not be applied without a seed"
+        );
+    }
+
+    #[test]
+    fn debounce_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .debounce(String::from("index"), TestPayload { a: true }, 10, 0)
+            .ok()
+            .unwrap();
+
+        assert!(scheduler.iterate(5).is_empty());
+
+        let same_task_id = scheduler
+            .debounce(String::from("index"), TestPayload { a: false }, 10, 5)
+            .ok()
+            .unwrap();
+        assert_eq!(task_id, same_task_id, "Debounce should reuse the task");
+
+        let other_task_id = scheduler
+            .debounce(String::from("other"), TestPayload { a: true }, 10, 5)
+            .ok()
+            .unwrap();
+        assert_ne!(task_id, other_task_id);
+
+        assert!(
+            scheduler.iterate(10).is_empty(),
+            "Debounced task should be postponed"
+        );
+
+        let tasks = scheduler.iterate(15);
+        assert_eq!(tasks.len(), 2);
+
+        let task = tasks.iter().find(|it| it.id == task_id).unwrap();
+        assert!(
+            !task.get_payload::<TestPayload>().unwrap().a,
+            "The latest payload should be used"
+        );
+
+        let new_task_id = scheduler
+            .debounce(String::from("index"), TestPayload { a: true }, 10, 20)
+            .ok()
+            .unwrap();
+        assert_ne!(
+            task_id, new_task_id,
+            "A new task should be created once the previous one fired"
+        );
+        assert_eq!(scheduler.debounced.as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn throttle_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .throttle(String::from("sync"), TestPayload { a: true }, 10, 0)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            scheduler.iterate(0).len(),
+            1,
+            "First call fires immediately"
+        );
+
+        let next_task_id = scheduler
+            .throttle(String::from("sync"), TestPayload { a: true }, 10, 3)
+            .ok()
+            .unwrap();
+        assert_ne!(task_id, next_task_id);
+
+        let same_task_id = scheduler
+            .throttle(String::from("sync"), TestPayload { a: false }, 10, 5)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            next_task_id, same_task_id,
+            "Calls within a window should be merged"
+        );
+
+        assert!(scheduler.iterate(9).is_empty());
+
+        let tasks = scheduler.iterate(10);
+        assert_eq!(tasks.len(), 1, "Merged call fires once the window passes");
+        assert!(!tasks[0].get_payload::<TestPayload>().unwrap().a);
+
+        scheduler
+            .throttle(String::from("other"), TestPayload { a: true }, 10, 20)
+            .unwrap();
+        assert_eq!(
+            scheduler.throttled.as_ref().unwrap().len(),
+            1,
+            "Keys which windows are over should be pruned"
+        );
+
+        scheduler.iterate(20);
+        let task_id = scheduler
+            .throttle(String::from("other"), TestPayload { a: true }, u64::MAX, 21)
+            .unwrap();
+        assert_eq!(
+            scheduler.next_run(task_id),
+            Some(u64::MAX),
+            "A huge window should saturate"
+        );
+    }
+
+    #[test]
+    fn rate_limit_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+        scheduler
+            .set_rate_limit(
+                String::from("emails"),
+                RateLimit {
+                    max_runs: 2,
+                    period_nano: 10,
+                },
+                0,
+            )
+            .unwrap();
+        assert!(scheduler
+            .set_rate_limit(
+                String::from("emails"),
+                RateLimit {
+                    max_runs: 0,
+                    period_nano: 10,
+                },
+                0,
+            )
+            .is_err());
+        assert!(scheduler
+            .set_rate_limit(
+                String::from("emails"),
+                RateLimit {
+                    max_runs: 2,
+                    period_nano: 0,
+                },
+                0,
+            )
+            .is_err());
+
+        for _ in 0..5 {
+            scheduler
+                .enqueue_with_options(
+                    TestPayload { a: true },
+                    SchedulingOptions::default(),
+                    EnqueueOptions {
+                        group: Some(String::from("emails")),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .ok()
+                .unwrap();
+        }
+
+        scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .ok()
+            .unwrap();
+
+        assert_eq!(
+            scheduler.iterate(0).len(),
+            3,
+            "Only two rate limited tasks and a task without a group should fire"
+        );
+        assert!(scheduler.iterate(4).is_empty());
+        let tasks = scheduler.iterate(5);
+        assert_eq!(
+            tasks.len(),
+            1,
+            "Tokens should be refilled evenly, not once per period"
+        );
+        let runs = scheduler.get_runs(tasks[0].id);
+        assert_eq!(runs[0].planned_at, 0);
+        assert_eq!(
+            runs[0].lateness_nano, 5,
+            "The delay caused by the rate limit should count as lateness"
+        );
+        assert_eq!(scheduler.iterate(10).len(), 1);
+        assert_eq!(scheduler.iterate(100).len(), 1);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn idempotent_enqueue_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let options = EnqueueOptions {
+            idempotency_key: Some(String::from("payment-42")),
+            ..Default::default()
+        };
+
+        let task_id = scheduler
+            .enqueue_with_options(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                options.clone(),
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        let same_task_id = scheduler
+            .enqueue_with_options(
+                TestPayload { a: true },
+                SchedulingOptions::default(),
+                options,
+                5,
+            )
+            .ok()
+            .unwrap();
+
+        assert_eq!(task_id, same_task_id, "Retried enqueue should be ignored");
+        assert_eq!(scheduler.tasks.len(), 1);
+
+        let alice = Principal::from_slice(&[1]);
+        assert!(
+            matches!(
+                scheduler.upsert(
+                    String::from("payment-42"),
+                    TestPayload { a: false },
+                    SchedulingOptions::default(),
+                    EnqueueOptions {
+                        owner: Some(alice),
+                        ..Default::default()
+                    },
+                    5,
+                ),
+                Err(CronError::NotOwner { .. })
+            ),
+            "Upsert should not change the owner"
+        );
+
+        let upserted_task_id = scheduler
+            .upsert(
+                String::from("payment-42"),
+                TestPayload { a: false },
+                SchedulingOptions {
+                    delay_nano: 20,
+                    interval_nano: 20,
+                    iterations: Iterations::Exact(1),
+                    ..Default::default()
+                },
+                EnqueueOptions {
+                    namespace: Some(String::from("payments")),
+                    ..Default::default()
+                },
+                5,
+            )
+            .ok()
+            .unwrap();
+
+        assert_eq!(task_id, upserted_task_id, "Upsert should keep the task id");
+        assert_eq!(
+            scheduler.get_task(&task_id).unwrap().namespace,
+            Some(String::from("payments"))
+        );
+        assert!(
+            scheduler.iterate(10).is_empty(),
+            "The old schedule should be replaced"
+        );
+
+        let tasks = scheduler.iterate(25);
+        assert_eq!(tasks.len(), 1);
+        assert!(!tasks[0].get_payload::<TestPayload>().unwrap().a);
+
+        assert!(scheduler.is_empty());
+        assert!(scheduler.find_by_idempotency_key("payment-42").is_none());
+    }
+
+    #[test]
+    fn reschedule_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Exact(3),
+                    ..Default::default()
+                },
+                0,
+            )
+            .ok()
+            .unwrap();
+
+        assert_eq!(scheduler.iterate(10).len(), 1);
+
+        assert!(scheduler
+            .reschedule(
+                task_id,
+                SchedulingOptions {
+                    delay_nano: 100,
+                    interval_nano: 100,
+                    iterations: Iterations::Exact(10),
+                    ..Default::default()
+                },
+                IterationsPolicy::Keep,
+                15,
+            )
+            .is_ok());
+        assert!(scheduler
+            .update_payload(task_id, TestPayload { a: false })
+            .is_ok());
+
+        assert!(
+            scheduler.iterate(20).is_empty(),
+            "The old queued run should be replaced"
+        );
+
+        let tasks = scheduler.iterate(115);
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, task_id, "Task id should be kept");
+        assert!(!tasks[0].get_payload::<TestPayload>().unwrap().a);
+
+        assert_eq!(scheduler.iterate(215).len(), 1);
+        assert!(
+            scheduler.get_task(&task_id).is_none(),
+            "The remaining iteration count should be kept"
+        );
+
+        assert_eq!(
+            scheduler.reschedule(
+                task_id,
+                SchedulingOptions::default(),
+                IterationsPolicy::Reset,
+                300
+            ),
+            Err(CronError::TaskNotFound(task_id))
+        );
+    }
+
+    #[test]
+    fn tags_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let enqueue = |scheduler: &mut TaskScheduler, tags: &[&str]| {
+            scheduler
+                .enqueue_with_options(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        delay_nano: 10,
+                        interval_nano: 10,
+                        iterations: Iterations::Exact(3),
+                        ..Default::default()
+                    },
+                    EnqueueOptions {
+                        tags: Some(tags.iter().map(|it| it.to_string()).collect()),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .ok()
+                .unwrap()
+        };
+
+        let task_1 = enqueue(&mut scheduler, &["user:1", "billing"]);
+        let task_2 = enqueue(&mut scheduler, &["user:1"]);
+        let task_3 = enqueue(&mut scheduler, &["user:2", "billing"]);
+
+        assert_eq!(
+            scheduler.get_task_ids_by_tag("user:1"),
+            vec![task_1, task_2]
+        );
+        assert_eq!(
+            scheduler.get_task_ids_by_tag("billing"),
+            vec![task_1, task_3]
+        );
+        assert!(scheduler.get_task_ids_by_tag("user:3").is_empty());
+
+        assert_eq!(scheduler.pause_by_tag("billing"), 2);
+        assert_eq!(scheduler.pause_by_tag("billing"), 0);
+
+        let tasks = scheduler.iterate(10);
+        assert_eq!(tasks.len(), 1, "Paused tasks should be skipped");
+        assert_eq!(tasks[0].id, task_2);
+
+        assert_eq!(scheduler.resume_by_tag("user:2", 15), 1);
+        assert_eq!(
+            scheduler.iterate(20).len(),
+            2,
+            "Resumed task should continue its cadence"
+        );
+
+        let dequeued = scheduler.dequeue_by_tag("user:1");
+        assert_eq!(dequeued.len(), 2);
+        assert!(scheduler.get_task_ids_by_tag("user:1").is_empty());
+        assert_eq!(scheduler.get_task_ids_by_tag("billing"), vec![task_3]);
+
+        assert_eq!(scheduler.iterate(30).len(), 1);
+        assert_eq!(
+            scheduler.iterate(40).len(),
+            1,
+            "Skipped runs should not count as iterations"
+        );
+        assert!(
+            scheduler.tag_index.as_ref().unwrap().is_empty(),
+            "Finished tasks should be removed from the index"
+        );
+    }
+
+    #[test]
+    fn pausing_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let one_shot = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+        let recurring = scheduler
+            .enqueue(
+                TestPayload { a: false },
+                SchedulingOptions {
+                    delay_nano: 10,
+                    interval_nano: 10,
+                    iterations: Iterations::Exact(2),
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+
+        assert!(scheduler.pause(one_shot).unwrap());
+        assert!(scheduler.pause(recurring).unwrap());
+        assert!(scheduler.iterate(10).is_empty());
+        assert!(
+            scheduler.queue.is_empty(),
+            "Paused tasks should be taken out of the queue"
+        );
+        assert_eq!(
+            scheduler.get_task(&one_shot).unwrap().paused_run_at,
+            Some(10)
+        );
+
+        assert!(scheduler.resume(one_shot, 25).unwrap());
+        assert!(scheduler.resume(recurring, 25).unwrap());
+        assert!(!scheduler.resume(recurring, 25).unwrap());
+        assert_eq!(scheduler.pause(100), Err(CronError::TaskNotFound(100)));
+        assert_eq!(
+            scheduler.next_run(one_shot),
+            Some(10),
+            "A resumed one-shot task should fire right away"
+        );
+        assert_eq!(
+            scheduler.next_run(recurring),
+            Some(30),
+            "A resumed task should keep its cadence"
+        );
+
+        assert_eq!(scheduler.iterate(25).len(), 1);
+        assert_eq!(scheduler.iterate(30).len(), 1);
+        assert_eq!(
+            scheduler.iterate(40).len(),
+            1,
+            "Skipped runs should not count as iterations"
+        );
+        assert!(scheduler.tasks.is_empty());
+    }
+
+    #[test]
+    fn ownership_and_quotas_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+
+        scheduler.default_quota = Some(TaskQuota {
+            max_active_tasks: Some(2),
+            min_interval_nano: Some(10),
+        });
+
+        let enqueue = |scheduler: &mut TaskScheduler, owner: Principal, interval_nano: u64| {
+            scheduler.enqueue_with_options(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                EnqueueOptions {
+                    owner: Some(owner),
+                    ..Default::default()
+                },
+                0,
+            )
+        };
+
+        assert!(
+            enqueue(&mut scheduler, alice, 5).is_err(),
+            "Interval should be limited"
+        );
+
+        let task_id = enqueue(&mut scheduler, alice, 10).ok().unwrap();
+        enqueue(&mut scheduler, alice, 10).ok().unwrap();
+        assert!(
+            enqueue(&mut scheduler, alice, 10).is_err(),
+            "Active tasks should be limited"
+        );
+
+        scheduler.set_quota(
+            bob,
+            TaskQuota {
+                max_active_tasks: Some(3),
+                min_interval_nano: None,
+            },
+        );
+        enqueue(&mut scheduler, bob, 1).ok().unwrap();
+
+        assert_eq!(scheduler.get_task_ids_by_owner(&alice).len(), 2);
+        assert_eq!(scheduler.get_task(&task_id).unwrap().owner, Some(alice));
+
+        assert!(scheduler.dequeue_owned(task_id, &bob).is_err());
+        assert!(scheduler
+            .reschedule_owned(
+                task_id,
+                SchedulingOptions::default(),
+                IterationsPolicy::Reset,
+                &bob,
+                0
+            )
+            .is_err());
+        assert!(scheduler
+            .update_payload_owned(task_id, TestPayload { a: false }, &bob)
+            .is_err());
+
+        assert!(scheduler.dequeue_owned(task_id, &alice).is_ok());
+        let task_id = enqueue(&mut scheduler, alice, 10).ok().unwrap();
+
+        assert!(
+            scheduler
+                .reschedule(
+                    task_id,
+                    SchedulingOptions {
+                        interval_nano: 5,
+                        iterations: Iterations::Infinite,
+                        ..Default::default()
+                    },
+                    IterationsPolicy::Reset,
+                    0
+                )
+                .is_err(),
+            "Rescheduling should respect the owner's quota"
+        );
+        assert!(
+            scheduler
+                .reschedule(
+                    task_id,
+                    SchedulingOptions {
+                        interval_nano: 5,
+                        ..Default::default()
+                    },
+                    IterationsPolicy::Keep,
+                    0
+                )
+                .is_err(),
+            "Kept iterations should be taken into account"
+        );
+
+        let upsert = |scheduler: &mut TaskScheduler, interval_nano: u64| {
+            scheduler.upsert(
+                String::from("key"),
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                EnqueueOptions {
+                    owner: Some(bob),
+                    ..Default::default()
+                },
+                0,
+            )
+        };
+        scheduler.set_quota(
+            bob,
+            TaskQuota {
+                max_active_tasks: Some(2),
+                min_interval_nano: Some(10),
+            },
+        );
+        assert!(upsert(&mut scheduler, 5).is_err());
+        let task_id = upsert(&mut scheduler, 10).unwrap();
+        assert!(
+            upsert(&mut scheduler, 5).is_err(),
+            "Upsert of an existing task should respect the owner's quota"
+        );
+        assert_eq!(
+            scheduler
+                .get_task(&task_id)
+                .unwrap()
+                .scheduling_options
+                .interval_nano,
+            10
+        );
+
+        assert!(
+            scheduler
+                .debounce_owned(String::from("key"), TestPayload { a: true }, 10, &bob, 0)
+                .is_err(),
+            "Debounced tasks should count towards the quota"
+        );
+        assert!(scheduler
+            .throttle_owned(String::from("key"), TestPayload { a: true }, 10, &bob, 0)
+            .is_err());
+
+        let carol = Principal::from_slice(&[3]);
+        let task_id = scheduler
+            .debounce_owned(String::from("key"), TestPayload { a: true }, 10, &carol, 0)
+            .unwrap();
+        assert_eq!(scheduler.get_task(&task_id).unwrap().owner, Some(carol));
+        assert!(
+            scheduler
+                .debounce_owned(String::from("key"), TestPayload { a: true }, 10, &bob, 0)
+                .is_err(),
+            "Only the owner should be able to debounce the task"
+        );
+    }
+
+    #[test]
+    fn fair_scheduling_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let mut weights = HashMap::new();
+        weights.insert(String::from("b"), 2);
+        scheduler.fair_scheduling = Some(FairScheduling { weights });
+
+        let mut enqueue = |namespace: &str, count: usize| -> Vec<TaskId> {
+            (0..count)
+                .map(|_| {
+                    scheduler
+                        .enqueue_with_options(
+                            TestPayload { a: true },
+                            SchedulingOptions::default(),
+                            EnqueueOptions {
+                                namespace: Some(namespace.to_string()),
+                                ..Default::default()
+                            },
+                            0,
+                        )
+                        .ok()
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        let a = enqueue("a", 100);
+        let b = enqueue("b", 100);
+        let c = enqueue("c", 1);
+
+        let namespace_of = |ids: &[TaskId]| {
+            if a.contains(&ids[0]) {
+                "a"
+            } else if b.contains(&ids[0]) {
+                "b"
+            } else if c.contains(&ids[0]) {
+                "c"
+            } else {
+                unreachable!()
+            }
+        };
+
+        let tasks = scheduler.iterate_limited(0, 6);
+        let namespaces: Vec<_> = tasks.iter().map(|t| namespace_of(&[t.id])).collect();
+
+        assert_eq!(
+            namespaces,
+            vec!["a", "b", "b", "c", "a", "b"],
+            "Namespaces should take turns according to their weights"
+        );
+    }
+
+    #[test]
+    fn validation_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let invalid = [
+            SchedulingOptions {
+                iterations: Iterations::Exact(0),
+                ..Default::default()
+            },
+            SchedulingOptions {
+                iterations: Iterations::Infinite,
+                ..Default::default()
+            },
+            SchedulingOptions {
+                delay_nano: u64::MAX,
+                ..Default::default()
+            },
+            SchedulingOptions {
+                start_at: Some(u64::MAX - 10),
+                interval_nano: 100,
+                iterations: Iterations::Exact(2),
+                ..Default::default()
+            },
+            SchedulingOptions {
+                start_at: Some(200),
+                end_at: Some(100),
+                ..Default::default()
+            },
+            SchedulingOptions {
+                start_at: Some(u64::MAX - 10),
+                interval_nano: 5,
+                jitter_nano: Some(5),
+                spread: Some(true),
+                ..Default::default()
+            },
+        ];
+
+        for options in invalid {
+            assert!(matches!(
+                scheduler.enqueue(TestPayload { a: true }, options, 10),
+                Err(CronError::InvalidSchedule(_))
+            ));
+        }
+
+        assert!(scheduler.is_empty(), "Invalid tasks shouldn't be enqueued");
+
+        let task_id = scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 10)
+            .unwrap();
+        assert_eq!(task_id, 0, "Rejected tasks shouldn't consume ids");
+
+        assert!(matches!(
+            scheduler.reschedule(
+                task_id,
+                SchedulingOptions {
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                IterationsPolicy::Reset,
+                20
+            ),
+            Err(CronError::InvalidSchedule(_))
+        ));
+        assert_eq!(
+            scheduler.iterate(10).len(),
+            1,
+            "The task should be kept intact"
+        );
+    }
+
+    #[test]
+    fn next_run_and_upcoming_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let options = SchedulingOptions {
+            delay_nano: 10,
+            interval_nano: 100,
+            iterations: Iterations::Exact(3),
+            ..Default::default()
+        };
+
+        assert_eq!(options.preview(0, 5), vec![10, 110, 210]);
+        assert_eq!(options.preview(0, 2), vec![10, 110]);
+
+        let task_id_1 = scheduler
+            .enqueue(TestPayload { a: true }, options, 0)
+            .unwrap();
+        let task_id_2 = scheduler
+            .enqueue(
+                TestPayload { a: false },
+                SchedulingOptions {
+                    delay_nano: 150,
+                    interval_nano: 50,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(scheduler.next_run(task_id_1), Some(10));
+        assert_eq!(scheduler.next_run(task_id_2), Some(150));
+        assert_eq!(scheduler.next_run(42), None);
+
+        let upcoming: Vec<_> = scheduler
+            .upcoming(100, 250, 10)
+            .into_iter()
+            .map(|it| (it.task_id, it.timestamp))
+            .collect();
+
+        assert_eq!(
+            upcoming,
+            vec![
+                (task_id_1, 110),
+                (task_id_2, 150),
+                (task_id_2, 200),
+                (task_id_1, 210),
+                (task_id_2, 250)
+            ]
+        );
+
+        assert_eq!(
+            scheduler
+                .upcoming(100, 250, 3)
+                .into_iter()
+                .map(|it| (it.task_id, it.timestamp))
+                .collect::<Vec<_>>(),
+            vec![(task_id_1, 110), (task_id_2, 150), (task_id_2, 200)],
+            "Only the earliest runs should be returned"
+        );
+        assert!(scheduler.upcoming(0, u64::MAX, 0).is_empty());
+
+        scheduler.iterate(10);
+        assert_eq!(scheduler.next_run(task_id_1), Some(110));
+
+        scheduler.pause(task_id_2).unwrap();
+        assert_eq!(scheduler.next_run(task_id_2), None);
+        assert_eq!(scheduler.upcoming(0, 1000, 10).len(), 2);
+    }
+
+    #[test]
+    fn run_log_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+        scheduler.set_run_log_capacity(3);
+
+        let task_id_1 = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+        let task_id_2 = scheduler
+            .enqueue(TestPayload { a: false }, SchedulingOptions::default(), 0)
+            .unwrap();
+
+        assert_eq!(scheduler.iterate(5).len(), 2);
+        scheduler.report_success(task_id_2);
+        scheduler.report_failure(task_id_1, String::from("Oops"));
+
+        let runs = scheduler.get_runs(task_id_1);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].planned_at, 0);
+        assert_eq!(runs[0].lateness_nano, 5);
+        assert_eq!(runs[0].outcome, RunOutcome::Failed);
+        assert_eq!(runs[0].error, Some(String::from("Oops")));
+        assert_eq!(
+            scheduler.get_runs(task_id_2)[0].outcome,
+            RunOutcome::Succeeded,
+            "Runs of removed tasks should be kept"
+        );
+
+        assert_eq!(scheduler.iterate(12).len(), 1);
+        assert_eq!(scheduler.iterate(20).len(), 1);
+
+        let bytes = encode_one(scheduler).unwrap();
+        let scheduler: TaskScheduler = decode_one(&bytes).unwrap();
+
+        let runs = scheduler.get_recent_runs(10);
+        assert_eq!(runs.len(), 3, "The oldest run should be evicted");
+        assert_eq!(runs[0].planned_at, 20);
+        assert_eq!(runs[0].outcome, RunOutcome::Pending);
+        assert_eq!(runs[1].lateness_nano, 2);
+        assert_eq!(runs[2].task_id, task_id_2);
+        assert_eq!(scheduler.get_recent_runs(1).len(), 1);
+    }
+
+    #[test]
+    fn metrics_work_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id_1 = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano: 2_000_000_000,
+                    iterations: Iterations::Infinite,
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+        let task_id_2 = scheduler
+            .enqueue(TestPayload { a: false }, SchedulingOptions::default(), 0)
+            .unwrap();
+        let task_id_3 = scheduler
+            .enqueue(
+                TestPayload { a: false },
+                SchedulingOptions {
+                    delay_nano: 10_000_000_000,
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+        scheduler.pause(task_id_3).unwrap();
+
+        let metrics = scheduler.get_metrics(0);
+        assert_eq!(metrics.tasks, 3);
+        assert_eq!(metrics.paused_tasks, 1);
+        assert_eq!(metrics.queue_length, 3);
+        assert_eq!(metrics.backlog, 2);
+
+        assert_eq!(scheduler.iterate_limited(3_000_000_000, 1).len(), 1);
+        assert_eq!(
+            scheduler.get_metrics(3_000_000_000).backlog,
+            2,
+            "The rest of due tasks should stay in the backlog"
+        );
+
+        let tasks = scheduler.iterate(3_000_000_000);
+        assert_eq!(tasks.len(), 2);
+
+        scheduler.report_success(task_id_1);
+        scheduler.report_failure(task_id_2, String::from("Oops"));
+
+        let metrics = scheduler.get_metrics(3_000_000_000);
+        assert_eq!(metrics.tasks, 2);
+        assert_eq!(metrics.queue_length, 2);
+        assert_eq!(metrics.backlog, 0);
+        assert_eq!(metrics.runs.executed, 3);
+        assert_eq!(metrics.runs.succeeded, 1);
+        assert_eq!(metrics.runs.failed, 1);
+        assert_eq!(metrics.runs.lateness_nano.total, 3);
+        assert_eq!(metrics.runs.lateness_nano.sum, 7_000_000_000);
+        assert_eq!(
+            metrics.runs.lateness_nano.counts,
+            vec![1, 0, 2, 0, 0, 0, 0, 0]
+        );
+
+        scheduler.dequeue(task_id_1);
+        scheduler.dequeue(task_id_3);
+
+        let metrics = scheduler.get_metrics(20_000_000_000);
+        assert_eq!(metrics.tasks, 0);
+        assert_eq!(
+            metrics.queue_length, 0,
+            "Runs of cancelled tasks shouldn't be queued"
+        );
+        assert_eq!(metrics.backlog, 0);
+    }
+
+    #[test]
+    fn prometheus_rendering_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
+        scheduler.iterate(1_500_000_000);
+
+        let metrics = scheduler.get_metrics(1_500_000_000).render_prometheus();
 
-        res
+        assert!(metrics.contains("ic_cron_tasks 0\n"));
+        assert!(metrics.contains("ic_cron_runs_total{kind=\"executed\"} 1\n"));
+        assert!(metrics.contains("ic_cron_run_lateness_seconds_bucket{le=\"1\"} 0\n"));
+        assert!(metrics.contains("ic_cron_run_lateness_seconds_bucket{le=\"2\"} 1\n"));
+        assert!(metrics.contains("ic_cron_run_lateness_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(metrics.contains("ic_cron_run_lateness_seconds_sum 1.5\n"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use ic_cdk::export::candid::{decode_one, encode_one};
-    use ic_cdk::export::candid::{CandidType, Deserialize};
+    #[test]
+    fn list_tasks_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        for _ in 0..5 {
+            scheduler
+                .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+                .unwrap();
+        }
 
-    use crate::task_scheduler::TaskScheduler;
-    use crate::types::{Iterations, SchedulingOptions};
+        let ids = |tasks: Vec<ScheduledTask>| tasks.iter().map(|it| it.id).collect::<Vec<_>>();
 
-    #[derive(CandidType, Deserialize)]
-    pub struct TestPayload {
-        pub a: bool,
+        assert_eq!(ids(scheduler.list_tasks(None, 2)), vec![0, 1]);
+        assert_eq!(ids(scheduler.list_tasks(Some(1), 2)), vec![2, 3]);
+        assert_eq!(ids(scheduler.list_tasks(Some(3), 2)), vec![4]);
+        assert!(scheduler.list_tasks(Some(4), 2).is_empty());
     }
 
     #[test]
-    fn main_flow_works_fine() {
+    fn trigger_now_works_fine() {
         let mut scheduler = TaskScheduler::default();
 
-        let task_id_1 = scheduler
+        let task_id = scheduler
             .enqueue(
                 TestPayload { a: true },
                 SchedulingOptions {
-                    delay_nano: 10,
-                    interval_nano: 10,
-                    iterations: Iterations::Exact(1),
+                    delay_nano: 100,
+                    interval_nano: 100,
+                    iterations: Iterations::Exact(2),
+                    ..Default::default()
                 },
                 0,
             )
-            .ok()
             .unwrap();
 
-        let task_id_2 = scheduler
-            .enqueue(
-                TestPayload { a: true },
-                SchedulingOptions {
-                    delay_nano: 10,
-                    interval_nano: 10,
-                    iterations: Iterations::Infinite,
-                },
-                0,
-            )
-            .ok()
-            .unwrap();
+        assert!(scheduler.trigger_now(task_id, 30).is_ok());
+        assert!(scheduler.trigger_now(task_id, 40).is_ok());
+        assert_eq!(
+            scheduler.trigger_now(42, 40),
+            Err(CronError::TaskNotFound(42))
+        );
+        assert_eq!(
+            scheduler
+                .upcoming(0, 500, 10)
+                .iter()
+                .map(|it| it.timestamp)
+                .collect::<Vec<_>>(),
+            vec![30, 100, 200],
+            "A manual run should be listed once, next to the regular runs"
+        );
 
-        let task_id_3 = scheduler
+        assert_eq!(
+            scheduler.iterate(50).len(),
+            1,
+            "Repeated triggers should result in a single manual run"
+        );
+        assert!(scheduler.iterate(60).is_empty());
+
+        assert_eq!(
+            scheduler.next_run(task_id),
+            Some(100),
+            "Cadence should be kept"
+        );
+        assert_eq!(scheduler.iterate(100).len(), 1);
+        assert_eq!(scheduler.iterate(200).len(), 1);
+        assert!(scheduler.is_empty());
+
+        scheduler.count_manual_runs = Some(true);
+        let task_id = scheduler
             .enqueue(
-                TestPayload { a: false },
+                TestPayload { a: true },
                 SchedulingOptions {
-                    delay_nano: 20,
-                    interval_nano: 20,
+                    delay_nano: 100,
+                    interval_nano: 100,
                     iterations: Iterations::Exact(2),
+                    ..Default::default()
                 },
                 0,
             )
-            .ok()
             .unwrap();
 
-        assert!(!scheduler.is_empty(), "Scheduler is not empty");
-
-        let tasks_emp = scheduler.iterate(5);
+        scheduler.trigger_now(task_id, 30).unwrap();
+        let tasks = scheduler.iterate(30);
+        assert_eq!(tasks[0].current_run.unwrap().remaining, Some(1));
         assert!(
-            tasks_emp.is_empty(),
-            "There should not be any tasks at timestamp 5"
+            matches!(
+                scheduler
+                    .get_task(&task_id)
+                    .unwrap()
+                    .scheduling_options
+                    .iterations,
+                Iterations::Exact(1)
+            ),
+            "Counted manual runs should decrement iterations"
         );
 
-        let tasks_1_2 = scheduler.iterate(10);
-        assert_eq!(
-            tasks_1_2.len(),
-            2,
-            "At timestamp 10 there should be 2 tasks"
-        );
+        scheduler.trigger_now(task_id, 40).unwrap();
+        let tasks = scheduler.iterate(40);
+        assert!(tasks[0].current_run.unwrap().is_final);
         assert!(
-            tasks_1_2.iter().any(|t| t.id == task_id_1),
-            "Should contain task 1"
+            scheduler.get_task(&task_id).is_none(),
+            "The final manual run should remove the task"
         );
         assert!(
-            tasks_1_2.iter().any(|t| t.id == task_id_2),
-            "Should contain task 2"
+            scheduler.is_empty(),
+            "No regular runs should be left in the queue"
         );
+        assert!(scheduler.iterate(100).is_empty());
+    }
 
-        let tasks_emp = scheduler.iterate(15);
-        assert!(
-            tasks_emp.is_empty(),
-            "There should not be any tasks at timestamp 15"
-        );
+    #[test]
+    fn query_tasks_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+        let alice = Principal::from_slice(&[1]);
+
+        for delay_nano in [50, 10, 40, 20, 30] {
+            scheduler
+                .enqueue_with_options(
+                    TestPayload { a: true },
+                    SchedulingOptions {
+                        delay_nano,
+                        ..Default::default()
+                    },
+                    EnqueueOptions {
+                        tags: Some(vec![String::from("tag")]),
+                        owner: Some(alice),
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .unwrap();
+        }
+        scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
+        scheduler.pause(4).unwrap();
+
+        let mut query = TaskQuery {
+            order: TaskOrder::NextRun,
+            filter: TaskFilter {
+                tag: Some(String::from("tag")),
+                ..Default::default()
+            },
+            limit: 2,
+            omit_payload: true,
+            ..Default::default()
+        };
+
+        let mut pages = vec![];
+        loop {
+            let page = scheduler.query_tasks(&query);
+            assert!(page.tasks.iter().all(|it| it.payload.data.is_empty()));
+            pages.push(page.tasks.iter().map(|it| it.id).collect::<Vec<_>>());
+
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, vec![vec![1, 3], vec![4, 2], vec![0]]);
+
+        let ids = |query: TaskQuery| {
+            scheduler
+                .query_tasks(&query)
+                .tasks
+                .iter()
+                .map(|it| it.id)
+                .collect::<Vec<_>>()
+        };
 
-        let tasks_2_3 = scheduler.iterate(20);
         assert_eq!(
-            tasks_2_3.len(),
-            2,
-            "At timestamp 20 there should be 2 tasks"
-        );
-        assert!(
-            tasks_2_3.iter().any(|t| t.id == task_id_2),
-            "Should contain task 2"
-        );
-        assert!(
-            tasks_2_3.iter().any(|t| t.id == task_id_3),
-            "Should contain task 3"
+            ids(TaskQuery {
+                filter: TaskFilter {
+                    owner: Some(alice),
+                    due_before: Some(35),
+                    ..Default::default()
+                },
+                limit: 10,
+                ..Default::default()
+            }),
+            vec![1, 3, 4]
         );
-
-        let tasks_2 = scheduler.iterate(30);
         assert_eq!(
-            tasks_2.len(),
-            1,
-            "There should be a single task at timestamp 30"
+            ids(TaskQuery {
+                filter: TaskFilter {
+                    state: Some(TaskState::Paused),
+                    ..Default::default()
+                },
+                limit: 10,
+                ..Default::default()
+            }),
+            vec![4]
         );
-        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
+        assert!(!scheduler
+            .query_tasks(&TaskQuery {
+                limit: 1,
+                ..Default::default()
+            })
+            .tasks[0]
+            .payload
+            .data
+            .is_empty());
 
-        let tasks_2_3 = scheduler.iterate(42);
+        for _ in 0..MAX_PAGE_SIZE * 2 {
+            scheduler
+                .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+                .unwrap();
+        }
         assert_eq!(
-            tasks_2_3.len(),
-            2,
-            "At timestamp 40 there should be 2 tasks"
+            scheduler.query_tasks(&TaskQuery::default()).tasks.len() as u64,
+            DEFAULT_PAGE_SIZE,
+            "Zero limit should mean the default page size"
         );
-        assert!(
-            tasks_2_3.iter().any(|t| t.id == task_id_2),
-            "Should contain task 2"
-        );
-        assert!(
-            tasks_2_3.iter().any(|t| t.id == task_id_3),
-            "Should contain task 3"
+        assert_eq!(
+            scheduler
+                .query_tasks(&TaskQuery {
+                    limit: u64::MAX,
+                    ..Default::default()
+                })
+                .tasks
+                .len() as u64,
+            MAX_PAGE_SIZE
         );
+    }
+
+    #[test]
+    fn run_info_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    delay_nano: 100,
+                    interval_nano: 100,
+                    iterations: Iterations::Exact(3),
+                    jitter_nano: Some(5),
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+
+        let run = |tasks: Vec<ScheduledTask>| tasks[0].current_run.unwrap();
 
-        let tasks_2 = scheduler.iterate(55);
         assert_eq!(
-            tasks_2.len(),
-            1,
-            "There should be a single task at timestamp 60"
+            run(scheduler.iterate(150)),
+            RunInfo {
+                index: 0,
+                planned_at: 100,
+                remaining: Some(2),
+                is_final: false,
+                is_manual: false,
+            }
         );
-        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
 
-        let tasks_2 = scheduler.iterate(60);
+        scheduler.trigger_now(task_id, 150).unwrap();
+        let manual_run = run(scheduler.iterate(150));
+        assert_eq!(manual_run.index, 1);
+        assert_eq!(manual_run.remaining, Some(2));
+        assert!(manual_run.is_manual);
+
+        let tasks = scheduler.iterate(250);
+        assert_eq!(tasks[0].get_run_count(), 3);
+        assert_eq!(run(tasks).remaining, Some(1));
+
+        let final_run = run(scheduler.iterate(350));
+        assert_eq!(final_run.index, 3);
+        assert_eq!(final_run.planned_at, 300);
+        assert_eq!(final_run.remaining, Some(0));
+        assert!(final_run.is_final);
+        assert!(scheduler.is_empty());
+
+        let task_id = scheduler
+            .enqueue(
+                TestPayload { a: true },
+                SchedulingOptions {
+                    interval_nano: 10,
+                    iterations: Iterations::Infinite,
+                    end_at: Some(15),
+                    ..Default::default()
+                },
+                0,
+            )
+            .unwrap();
+
+        let tasks = scheduler.iterate(0);
+        assert_eq!(tasks[0].id, task_id);
+        assert_eq!(run(tasks).remaining, None);
+
+        let final_run = run(scheduler.iterate(10));
+        assert!(
+            final_run.is_final,
+            "The last run before end_at should be final"
+        );
         assert_eq!(
-            tasks_2.len(),
-            1,
-            "There should be a single task at timestamp 60"
+            final_run.remaining, None,
+            "Infinite tasks shouldn't have remaining runs"
         );
-        assert_eq!(tasks_2[0].id, task_id_2, "Should contain task 2");
-
-        scheduler.dequeue(task_id_2).unwrap();
 
         scheduler
             .enqueue(
                 TestPayload { a: true },
                 SchedulingOptions {
-                    delay_nano: 10,
                     interval_nano: 10,
-                    iterations: Iterations::Exact(1),
+                    iterations: Iterations::Exact(5),
+                    end_at: Some(35),
+                    ..Default::default()
                 },
-                0,
+                20,
             )
-            .ok()
             .unwrap();
+
+        assert_eq!(run(scheduler.iterate(20)).remaining, Some(4));
+        let final_run = run(scheduler.iterate(30));
+        assert!(final_run.is_final);
+        assert_eq!(
+            final_run.remaining,
+            Some(3),
+            "Iterations cut by end_at should still be counted"
+        );
+        assert!(scheduler.tasks.is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct TestObserver {
+        events: Rc<RefCell<Vec<(TaskId, TaskEvent)>>>,
+    }
+
+    impl CronObserver for TestObserver {
+        fn on_event(&self, task: &ScheduledTask, event: TaskEvent) {
+            self.events.borrow_mut().push((task.id, event));
+        }
     }
 
     #[test]
-    fn delay_works_fine() {
+    fn observer_works_fine() {
         let mut scheduler = TaskScheduler::default();
+        let observer = TestObserver::default();
+        scheduler.set_observer(observer.clone());
+
+        let options = SchedulingOptions {
+            delay_nano: 10,
+            interval_nano: 10,
+            iterations: Iterations::Infinite,
+            end_at: Some(25),
+            ..Default::default()
+        };
 
         let task_id_1 = scheduler
+            .enqueue(TestPayload { a: true }, options, 0)
+            .unwrap();
+        let task_id_2 = scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
+        let task_id_3 = scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 100)
+            .unwrap();
+
+        scheduler
+            .reschedule(task_id_1, options, IterationsPolicy::Reset, 0)
+            .unwrap();
+        scheduler.dequeue(task_id_3);
+        scheduler.iterate(10);
+        scheduler.iterate(20);
+
+        let task_id_4 = scheduler
             .enqueue(
                 TestPayload { a: true },
                 SchedulingOptions {
-                    delay_nano: 10,
-                    interval_nano: 20,
+                    delay_nano: 100,
+                    interval_nano: 100,
                     iterations: Iterations::Infinite,
+                    mode: Some(SchedulingMode::FixedDelay),
+                    ..Default::default()
                 },
                 0,
             )
-            .ok()
             .unwrap();
+        scheduler.pause(task_id_4).unwrap();
+        scheduler.resume(task_id_4, 30).unwrap();
+        scheduler.trigger_now(task_id_4, 30).unwrap();
+        scheduler.iterate(30);
+        scheduler.iterate(100);
+        scheduler.complete(task_id_4, 110).unwrap();
+        for _ in 0..DEFAULT_QUARANTINE_THRESHOLD {
+            scheduler.report_failure(task_id_4, String::from("Oops"));
+        }
+        scheduler.release_quarantine(task_id_4, 120).unwrap();
 
-        let tasks = scheduler.iterate(5);
-
-        assert!(
-            tasks.is_empty(),
-            "There shouldn't be any task at this timestamp (5)"
+        assert_eq!(
+            *observer.events.borrow(),
+            vec![
+                (task_id_1, TaskEvent::Enqueued),
+                (task_id_2, TaskEvent::Enqueued),
+                (task_id_3, TaskEvent::Enqueued),
+                (task_id_1, TaskEvent::Rescheduled),
+                (task_id_3, TaskEvent::Cancelled),
+                (task_id_2, TaskEvent::Fired),
+                (task_id_2, TaskEvent::Completed),
+                (task_id_1, TaskEvent::Fired),
+                (task_id_1, TaskEvent::Fired),
+                (task_id_1, TaskEvent::Expired),
+                (task_id_4, TaskEvent::Enqueued),
+                (task_id_4, TaskEvent::Paused),
+                (task_id_4, TaskEvent::Resumed),
+                (task_id_4, TaskEvent::Triggered),
+                (task_id_4, TaskEvent::Fired),
+                (task_id_4, TaskEvent::Fired),
+                (task_id_4, TaskEvent::Rescheduled),
+                (task_id_4, TaskEvent::Failed),
+                (task_id_4, TaskEvent::Failed),
+                (task_id_4, TaskEvent::Failed),
+                (task_id_4, TaskEvent::Quarantined),
+                (task_id_4, TaskEvent::Released),
+            ],
+            "The observer should be notified about every lifecycle event"
         );
 
-        let tasks = scheduler.iterate(10);
+        let events = observer.events.borrow().len();
+        let bytes = encode_one(scheduler).unwrap();
+        let mut scheduler: TaskScheduler = decode_one(&bytes).unwrap();
+        scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
+
         assert_eq!(
-            tasks.len(),
-            1,
-            "There should be a task that was triggered by a delay at this timestamp (10)"
+            observer.events.borrow().len(),
+            events,
+            "Observers shouldn't be persisted"
         );
+    }
 
-        let tasks = scheduler.iterate(20);
+    #[test]
+    fn event_log_works_fine() {
+        let mut scheduler = TaskScheduler::default();
+
+        scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
         assert!(
-            tasks.is_empty(),
-            "There shouldn't be any task at this timestamp (20)"
+            scheduler.get_events(0, 100).is_empty(),
+            "The event log should be disabled by default"
         );
 
-        let tasks = scheduler.iterate(30);
+        let mut scheduler = TaskScheduler::default();
+        scheduler.set_event_log_retention(100);
+
+        let task_id_1 = scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
+            .unwrap();
+        let task_id_2 = scheduler
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 100)
+            .unwrap();
+
+        scheduler.iterate(0);
+        scheduler.report_failure(task_id_1, String::from("boom"));
+        scheduler.dequeue(task_id_2);
+
+        let bytes = encode_one(scheduler).unwrap();
+        let mut scheduler: TaskScheduler = decode_one(&bytes).unwrap();
+
+        let events = scheduler.get_events(0, 100);
         assert_eq!(
-            tasks.len(),
-            1,
-            "There should be a task that was triggered by an interval at this timestamp (30)"
+            events
+                .iter()
+                .map(|e| (e.seq, e.task_id, e.event))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, task_id_1, TaskEvent::Enqueued),
+                (1, task_id_2, TaskEvent::Enqueued),
+                (2, task_id_1, TaskEvent::Fired),
+                (3, task_id_1, TaskEvent::Completed),
+                (4, task_id_1, TaskEvent::Failed),
+                (5, task_id_2, TaskEvent::Cancelled),
+            ],
+            "Events should be persisted with consecutive sequence numbers"
         );
+        assert_eq!(events[4].error, Some(String::from("boom")));
 
-        let tasks = scheduler.iterate(50);
+        let events = scheduler.get_events(3, 2);
         assert_eq!(
-            tasks.len(),
-            1,
-            "There should be a task that was triggered by an interval at this timestamp (50)"
+            events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![3, 4],
+            "Events should be returned starting from the requested seq"
         );
-    }
+        assert!(scheduler.get_events(6, 100).is_empty());
 
-    #[test]
-    fn ser_de_works_fine() {
-        let mut scheduler = TaskScheduler::default();
+        scheduler.prune_events(2);
+        assert_eq!(
+            scheduler.get_events(0, 1)[0].seq,
+            2,
+            "Pruned events should be skipped"
+        );
 
+        scheduler.set_event_log_retention(2);
         scheduler
-            .enqueue(
-                TestPayload { a: true },
-                SchedulingOptions {
-                    delay_nano: 10,
-                    interval_nano: 20,
-                    iterations: Iterations::Infinite,
-                },
-                0,
-            )
-            .ok()
+            .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 0)
             .unwrap();
 
-        let bytes = encode_one(scheduler).expect("Should be able to encode task scheduler");
-        let mut scheduler: TaskScheduler =
-            decode_one(&bytes).expect("Should be able to decode task scheduler");
+        assert_eq!(
+            scheduler
+                .get_events(0, 100)
+                .iter()
+                .map(|e| e.seq)
+                .collect::<Vec<_>>(),
+            vec![5, 6],
+            "Only the most recent events should be retained"
+        );
 
-        let tasks = scheduler.iterate(10);
+        scheduler.set_event_log_retention(MAX_EVENTS_PAGE_SIZE as u64 * 2);
+        for _ in 0..MAX_EVENTS_PAGE_SIZE {
+            scheduler
+                .enqueue(TestPayload { a: true }, SchedulingOptions::default(), 100)
+                .unwrap();
+        }
 
         assert_eq!(
-            tasks.len(),
-            1,
-            "There should be a task that was triggered by a delay at this timestamp (10)"
+            scheduler.get_events(0, usize::MAX).len(),
+            MAX_EVENTS_PAGE_SIZE,
+            "The page size should be capped"
         );
     }
 }
@@ -1,13 +1,54 @@
-use std::cmp::{max, min, Ordering};
-use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::ops::Bound;
 
 use ic_cdk::export::candid::types::{Serializer, Type};
-use ic_cdk::export::candid::{
-    decode_one, encode_one, CandidType, Deserialize, Result as CandidResult,
-};
+use ic_cdk::export::candid::{decode_one, encode_one, CandidType, Deserialize, Principal};
+use ic_cdk::export::serde::Deserializer;
 
 pub type TaskId = u64;
 
+pub type CronResult<T> = Result<T, CronError>;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CronError {
+    /// A payload can't be encoded to or decoded from Candid
+    Serialization(String),
+    InvalidSchedule(String),
+    TaskNotFound(TaskId),
+    NotOwner {
+        task_id: TaskId,
+        caller: Principal,
+    },
+    QuotaExceeded(String),
+}
+
+impl Display for CronError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CronError::Serialization(msg) => write!(f, "Serialization error: {}", msg),
+            CronError::InvalidSchedule(msg) => write!(f, "Invalid schedule: {}", msg),
+            CronError::TaskNotFound(task_id) => write!(f, "Task {} not found", task_id),
+            CronError::NotOwner { task_id, caller } => write!(
+                f,
+                "Principal {} is not the owner of task {}",
+                caller, task_id
+            ),
+            CronError::QuotaExceeded(msg) => write!(f, "Quota exceeded: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CronError {}
+
+impl From<ic_cdk::export::candid::Error> for CronError {
+    fn from(err: ic_cdk::export::candid::Error) -> Self {
+        CronError::Serialization(err.to_string())
+    }
+}
+
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Task {
     pub data: Vec<u8>,
@@ -19,11 +60,287 @@ pub enum Iterations {
     Exact(u64),
 }
 
+/// Whether rescheduling a task resets its remaining iterations to the new `SchedulingOptions::iterations`
+#[derive(Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum IterationsPolicy {
+    Reset,
+    Keep,
+}
+
 #[derive(Clone, Copy, CandidType, Deserialize)]
 pub struct SchedulingOptions {
     pub delay_nano: u64,
     pub interval_nano: u64,
     pub iterations: Iterations,
+    /// Tasks with a higher priority are returned first among tasks that are ready at the same time
+    pub priority: Option<u32>,
+    /// Absolute timestamp of the first execution, `delay_nano` is ignored when set
+    pub start_at: Option<u64>,
+    /// Absolute timestamp after which the task is no longer executed
+    pub end_at: Option<u64>,
+    /// Snaps each execution timestamp to the next boundary
+    pub alignment: Option<Alignment>,
+    /// Whether the next run is planned relative to the previous planned run (default) or to the moment the
+    /// previous run is completed
+    pub mode: Option<SchedulingMode>,
+    /// Each run is delayed by a random offset from `0` to `jitter_nano`
+    pub jitter_nano: Option<u64>,
+    /// Shifts the whole schedule by a random offset from `0` to `interval_nano`, so tasks with the same
+    /// schedule don't fire at once
+    pub spread: Option<bool>,
+}
+
+impl SchedulingOptions {
+    #[inline(always)]
+    pub fn get_priority(&self) -> u32 {
+        self.priority.unwrap_or_default()
+    }
+
+    #[inline(always)]
+    pub fn is_recurring(&self) -> bool {
+        match self.iterations {
+            Iterations::Infinite => true,
+            Iterations::Exact(times) => times > 1,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get_mode(&self) -> SchedulingMode {
+        self.mode.unwrap_or_default()
+    }
+
+    pub fn first_run_at(&self, enqueued_at: u64) -> u64 {
+        self.align(
+            self.start_at
+                .unwrap_or_else(|| enqueued_at.saturating_add(self.delay_nano)),
+        )
+        .unwrap_or(u64::MAX)
+    }
+
+    /// Returns `None` if the next run doesn't fit into `u64`
+    pub fn next_run_after(&self, planned_at: u64) -> Option<u64> {
+        planned_at
+            .checked_add(self.interval_nano)
+            .and_then(|it| self.align(it))
+    }
+
+    /// Planned timestamps of the remaining runs, starting from `first_run_at`. Jitter and spread are not
+    /// applied, `FixedDelay` runs are planned as if each run was completed instantly.
+    pub fn planned_runs(&self, first_run_at: u64) -> impl Iterator<Item = u64> {
+        let options = *self;
+        let runs = match self.iterations {
+            Iterations::Infinite => usize::MAX,
+            Iterations::Exact(times) => usize::try_from(times).unwrap_or(usize::MAX),
+        };
+
+        std::iter::successors(Some(first_run_at), move |prev| {
+            options.next_run_after(*prev).filter(|next| next > prev)
+        })
+        .take(runs)
+        .take_while(move |it| options.is_before_end(*it))
+    }
+
+    /// Up to `n` planned timestamps of a task, if it was enqueued at `start` with these options
+    pub fn preview(&self, start: u64, n: usize) -> Vec<u64> {
+        self.planned_runs(self.first_run_at(start))
+            .take(n)
+            .collect()
+    }
+
+    /// Rejects options which would never fire as intended: a task without iterations, an infinite
+    /// task without an interval, an empty time window or timestamps which don't fit into `u64`
+    pub fn validate(&self, enqueued_at: u64) -> CronResult<()> {
+        if matches!(self.iterations, Iterations::Exact(0)) {
+            return Err(CronError::InvalidSchedule(String::from(
+                "Tasks should have at least one iteration",
+            )));
+        }
+
+        if matches!(self.iterations, Iterations::Infinite) && self.interval_nano == 0 {
+            return Err(CronError::InvalidSchedule(String::from(
+                "Infinite tasks should have a non-zero interval_nano",
+            )));
+        }
+
+        let latest_run_at = match self.start_at {
+            Some(start_at) => Some(start_at),
+            None => enqueued_at.checked_add(self.delay_nano),
+        }
+        .and_then(|it| it.checked_add(self.alignment.map_or(0, |it| it.period_nano)))
+        .and_then(|it| it.checked_add(self.interval_nano))
+        .and_then(|it| it.checked_add(self.max_run_offset()));
+
+        if latest_run_at.is_none() {
+            return Err(CronError::InvalidSchedule(String::from(
+                "Scheduled timestamps overflow u64",
+            )));
+        }
+
+        let first_run_at = self.first_run_at(enqueued_at);
+
+        if !self.is_before_end(first_run_at.saturating_add(self.max_run_offset())) {
+            return Err(CronError::InvalidSchedule(String::from(
+                "end_at is earlier than the first run (including jitter and spread)",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The greatest possible `run_offset()`
+    pub fn max_run_offset(&self) -> u64 {
+        let spread = match self.spread.unwrap_or_default() {
+            true => self.interval_nano.saturating_sub(1),
+            false => 0,
+        };
+
+        spread.saturating_add(self.jitter_nano.unwrap_or_default())
+    }
+
+    /// How much later than `planned_at` the run actually happens, deterministic for the same `seed`.
+    /// Jitter and spread are skipped until the seed is initialized.
+    pub fn run_offset(&self, seed: Option<u64>, task_id: TaskId, planned_at: u64) -> u64 {
+        let seed = match seed {
+            Some(seed) => seed,
+            None => return 0,
+        };
+        let mut offset = 0u64;
+
+        if self.spread.unwrap_or_default() && self.interval_nano > 0 {
+            offset = mix(seed ^ mix(task_id)) % self.interval_nano;
+        }
+
+        if let Some(jitter_nano) = self.jitter_nano.filter(|it| *it > 0) {
+            let random = mix(seed ^ mix(task_id ^ mix(planned_at)));
+
+            offset = offset.saturating_add(match jitter_nano.checked_add(1) {
+                Some(bound) => random % bound,
+                None => random,
+            });
+        }
+
+        offset
+    }
+
+    /// The first run starting from `planned_at` which is not earlier than `timestamp`, runs before
+    /// `timestamp` are skipped
+    pub fn next_run_since(&self, planned_at: u64, timestamp: u64) -> u64 {
+        if self.interval_nano == 0 || planned_at >= timestamp {
+            return planned_at;
+        }
+
+        let skipped = (timestamp - planned_at) / self.interval_nano;
+        let mut next = self
+            .align(planned_at.saturating_add(skipped.saturating_mul(self.interval_nano)))
+            .unwrap_or(u64::MAX);
+
+        while next < timestamp {
+            match self.next_run_after(next) {
+                Some(it) if it > next => next = it,
+                _ => break,
+            }
+        }
+
+        next
+    }
+
+    #[inline(always)]
+    fn align(&self, timestamp: u64) -> Option<u64> {
+        self.alignment
+            .map_or(Some(timestamp), |alignment| alignment.align(timestamp))
+    }
+
+    #[inline(always)]
+    pub fn is_before_end(&self, timestamp: u64) -> bool {
+        self.end_at.map_or(true, |end_at| timestamp <= end_at)
+    }
+}
+
+impl Default for SchedulingOptions {
+    fn default() -> Self {
+        Self {
+            delay_nano: 0,
+            interval_nano: 0,
+            iterations: Iterations::Exact(1),
+            priority: None,
+            start_at: None,
+            end_at: None,
+            alignment: None,
+            mode: None,
+            jitter_nano: None,
+            spread: None,
+        }
+    }
+}
+
+// splitmix64 finalizer
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    z ^ (z >> 31)
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SchedulingMode {
+    /// The next run is planned relative to the previous planned run
+    #[default]
+    FixedRate,
+    /// The next run is planned relative to the moment the previous run is completed
+    FixedDelay,
+}
+
+pub const NANOS_IN_MINUTE: u64 = 1_000_000_000 * 60;
+pub const NANOS_IN_HOUR: u64 = NANOS_IN_MINUTE * 60;
+pub const NANOS_IN_DAY: u64 = NANOS_IN_HOUR * 24;
+
+/// Epoch-aligned boundaries: timestamps `k * period_nano + offset_nano`
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub struct Alignment {
+    pub period_nano: u64,
+    pub offset_nano: u64,
+}
+
+impl Alignment {
+    pub fn minute() -> Self {
+        Self {
+            period_nano: NANOS_IN_MINUTE,
+            offset_nano: 0,
+        }
+    }
+
+    pub fn hour() -> Self {
+        Self {
+            period_nano: NANOS_IN_HOUR,
+            offset_nano: 0,
+        }
+    }
+
+    pub fn day() -> Self {
+        Self {
+            period_nano: NANOS_IN_DAY,
+            offset_nano: 0,
+        }
+    }
+
+    /// Returns the first boundary which is not earlier than `timestamp`, `None` if it doesn't fit
+    /// into `u64`
+    pub fn align(&self, timestamp: u64) -> Option<u64> {
+        if self.period_nano == 0 {
+            return Some(timestamp);
+        }
+
+        let offset = self.offset_nano % self.period_nano;
+        let since_boundary =
+            (timestamp % self.period_nano + self.period_nano - offset) % self.period_nano;
+
+        if since_boundary == 0 {
+            Some(timestamp)
+        } else {
+            timestamp.checked_add(self.period_nano - since_boundary)
+        }
+    }
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -34,6 +351,28 @@ pub struct ScheduledTask {
     pub rescheduled_at: Option<u64>,
     pub scheduling_options: SchedulingOptions,
     pub delay_passed: bool,
+    pub consecutive_failures: Option<u32>,
+    pub quarantined: Option<bool>,
+    pub awaiting_completion: Option<bool>,
+    /// Jitter and spread applied to the queued run
+    pub run_offset: Option<u64>,
+    /// The original timestamp of the queued run, if it was deferred by a rate limit
+    pub deferred_from: Option<u64>,
+    pub debounce_key: Option<String>,
+    pub group: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub paused: Option<bool>,
+    /// Planned timestamp of the next run of a paused task, which was taken out of the queue
+    pub paused_run_at: Option<u64>,
+    pub owner: Option<Principal>,
+    pub namespace: Option<String>,
+    /// When the task was manually triggered, if its manual run is still queued
+    pub triggered_at: Option<u64>,
+    /// How many times the task was executed, including manual runs
+    pub run_count: Option<u64>,
+    /// Only set on tasks returned for execution
+    pub current_run: Option<RunInfo>,
 }
 
 impl ScheduledTask {
@@ -43,9 +382,9 @@ impl ScheduledTask {
         scheduled_at: u64,
         rescheduled_at: Option<u64>,
         scheduling_interval: SchedulingOptions,
-    ) -> CandidResult<Self> {
+    ) -> CronResult<Self> {
         let task = Task {
-            data: encode_one(payload).unwrap(),
+            data: encode_one(payload)?,
         };
 
         Ok(Self {
@@ -55,21 +394,667 @@ impl ScheduledTask {
             rescheduled_at,
             scheduling_options: scheduling_interval,
             delay_passed: false,
+            consecutive_failures: None,
+            quarantined: None,
+            awaiting_completion: None,
+            run_offset: None,
+            deferred_from: None,
+            debounce_key: None,
+            group: None,
+            idempotency_key: None,
+            tags: None,
+            paused: None,
+            paused_run_at: None,
+            owner: None,
+            namespace: None,
+            triggered_at: None,
+            run_count: None,
+            current_run: None,
         })
     }
 
-    pub fn get_payload<'a, T>(&'a self) -> CandidResult<T>
+    #[inline(always)]
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined.unwrap_or_default()
+    }
+
+    #[inline(always)]
+    pub fn is_paused(&self) -> bool {
+        self.paused.unwrap_or_default()
+    }
+
+    #[inline(always)]
+    pub fn is_awaiting_completion(&self) -> bool {
+        self.awaiting_completion.unwrap_or_default()
+    }
+
+    #[inline(always)]
+    pub fn get_run_count(&self) -> u64 {
+        self.run_count.unwrap_or_default()
+    }
+
+    pub fn get_state(&self) -> TaskState {
+        if self.is_quarantined() {
+            TaskState::Quarantined
+        } else if self.is_paused() {
+            TaskState::Paused
+        } else if self.is_awaiting_completion() {
+            TaskState::AwaitingCompletion
+        } else {
+            TaskState::Active
+        }
+    }
+
+    pub fn get_payload<'a, T>(&'a self) -> CronResult<T>
     where
         T: Deserialize<'a> + CandidType,
     {
-        decode_one(&self.payload.data)
+        Ok(decode_one(&self.payload.data)?)
+    }
+
+    pub fn set_payload<T: CandidType>(&mut self, payload: T) -> CronResult<()> {
+        self.payload.data = encode_one(payload)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TaskEvent {
+    Enqueued,
+    /// The task was returned for execution
+    Fired,
+    /// The task was rescheduled, postponed, its first run was replaced or the next run of a `FixedDelay`
+    /// task was planned on completion
+    Rescheduled,
+    /// A manual run of the task was queued with `trigger_now()`
+    Triggered,
+    Paused,
+    Resumed,
+    /// The task failed too many times in a row and is no longer executed
+    Quarantined,
+    /// The task was released from quarantine
+    Released,
+    /// The task was removed after its last iteration
+    Completed,
+    /// The task was dequeued
+    Cancelled,
+    /// The task was removed because its next run would happen after `end_at`
+    Expired,
+    /// A run of the task was reported as failed
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CronEvent {
+    pub seq: u64,
+    pub task_id: TaskId,
+    pub event: TaskEvent,
+    pub error: Option<String>,
+}
+
+/// An append-only log of events with consecutive sequence numbers, the oldest events are pruned
+/// once there are more than `max_events` of them
+#[derive(Clone, CandidType, Deserialize)]
+pub struct EventLog {
+    next_seq: u64,
+    max_events: u64,
+    events: VecDeque<CronEvent>,
+}
+
+impl EventLog {
+    pub fn new(max_events: u64) -> Self {
+        Self {
+            next_seq: 0,
+            max_events,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Returns the sequence number of the event
+    pub fn push(&mut self, task_id: TaskId, event: TaskEvent, error: Option<String>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if self.max_events > 0 {
+            self.truncate(self.max_events - 1);
+            self.events.push_back(CronEvent {
+                seq,
+                task_id,
+                event,
+                error,
+            });
+        }
+
+        seq
+    }
+
+    pub fn get(&self, from_seq: u64, limit: usize) -> Vec<CronEvent> {
+        let first_seq = match self.events.front() {
+            Some(event) => event.seq,
+            None => return vec![],
+        };
+
+        let skip = from_seq
+            .saturating_sub(first_seq)
+            .min(self.events.len() as u64) as usize;
+
+        self.events.range(skip..).take(limit).cloned().collect()
+    }
+
+    pub fn prune_before(&mut self, seq: u64) {
+        while self.events.front().is_some_and(|event| event.seq < seq) {
+            self.events.pop_front();
+        }
+    }
+
+    #[inline(always)]
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn set_max_events(&mut self, max_events: u64) {
+        self.max_events = max_events;
+        self.truncate(max_events);
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn truncate(&mut self, len: u64) {
+        while self.events.len() as u64 > len {
+            self.events.pop_front();
+        }
+    }
+}
+
+/// Is notified by `TaskScheduler` about lifecycle events of tasks. Shouldn't access the scheduler
+/// itself, since it is notified in the middle of its operations.
+pub trait CronObserver {
+    fn on_event(&self, task: &ScheduledTask, event: TaskEvent);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RunInfo {
+    /// `0` for the first run of the task
+    pub index: u64,
+    /// When the run was planned, without jitter and spread
+    pub planned_at: u64,
+    /// How many iterations are left after this one, `None` for `Infinite` tasks. `end_at` is not
+    /// taken into account, so a run which is final because of it may have some iterations left.
+    pub remaining: Option<u64>,
+    /// Whether the task is removed after this run, because of `iterations` or `end_at`
+    pub is_final: bool,
+    /// Whether the run was caused by `trigger_now()`
+    pub is_manual: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TaskState {
+    Active,
+    Paused,
+    Quarantined,
+    /// A `FixedDelay` task which is being handled
+    AwaitingCompletion,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub enum TaskOrder {
+    #[default]
+    Id,
+    /// Tasks without a queued run go last
+    NextRun,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct TaskFilter {
+    pub state: Option<TaskState>,
+    pub tag: Option<String>,
+    pub owner: Option<Principal>,
+    /// Only tasks with the next run earlier than this timestamp
+    pub due_before: Option<u64>,
+}
+
+/// Points right after the last task of a page, shouldn't be reused with a different `TaskOrder`
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub struct TaskCursor {
+    pub key: u64,
+    pub task_id: TaskId,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct TaskQuery {
+    pub order: TaskOrder,
+    pub filter: TaskFilter,
+    /// `TaskPage::next_cursor` of the previous page, `None` for the first page
+    pub cursor: Option<TaskCursor>,
+    /// The page size, `0` means `DEFAULT_PAGE_SIZE`, capped at `MAX_PAGE_SIZE`
+    pub limit: u64,
+    /// Returns tasks with empty payloads, so a page fits into a response
+    pub omit_payload: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TaskPage {
+    pub tasks: Vec<ScheduledTask>,
+    /// `None` if there are no more tasks
+    pub next_cursor: Option<TaskCursor>,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct EnqueueOptions {
+    pub group: Option<String>,
+    /// While a task with this key exists, enqueueing another task with the same key returns its id
+    pub idempotency_key: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// A principal which enqueued the task, subject to `TaskScheduler` quotas
+    pub owner: Option<Principal>,
+    pub namespace: Option<String>,
+}
+
+/// Namespaces take turns when ready tasks are popped, each popping up to its weight (`1` by default)
+/// tasks in a row, so a single namespace can't starve the others under a per-tick budget
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct FairScheduling {
+    pub weights: HashMap<String, u32>,
+}
+
+impl FairScheduling {
+    #[inline(always)]
+    pub fn weight_of(&self, namespace: &str) -> u32 {
+        self.weights.get(namespace).copied().unwrap_or(1)
+    }
+}
+
+#[derive(Clone, Copy, Default, CandidType, Deserialize)]
+pub struct TaskQuota {
+    pub max_active_tasks: Option<u64>,
+    pub min_interval_nano: Option<u64>,
+}
+
+/// On average `max_runs` tasks per `period_nano`, with bursts of up to `max_runs` tasks at once.
+/// Right after a burst the next run is allowed in `period_nano / max_runs`, so up to about
+/// `2 * max_runs` tasks can fire within a single period.
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub struct RateLimit {
+    pub max_runs: u64,
+    pub period_nano: u64,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> CronResult<()> {
+        if self.max_runs == 0 || self.period_nano == 0 {
+            return Err(CronError::InvalidSchedule(String::from(
+                "Rate limits should have a non-zero max_runs and period_nano",
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// A bucket of `max_runs` tokens, which are refilled evenly during `period_nano`
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TokenBucket {
+    pub limit: RateLimit,
+    pub tokens: u64,
+    pub refilled_at: u64,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, timestamp: u64) -> Self {
+        Self {
+            limit,
+            tokens: limit.max_runs,
+            refilled_at: timestamp,
+        }
+    }
+
+    pub fn try_take(&mut self, timestamp: u64) -> bool {
+        self.refill(timestamp);
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+
+        true
+    }
+
+    #[inline(always)]
+    pub fn next_token_at(&self) -> u64 {
+        self.refilled_at + self.token_interval()
+    }
+
+    fn refill(&mut self, timestamp: u64) {
+        if timestamp <= self.refilled_at {
+            return;
+        }
+
+        let new_tokens = (timestamp - self.refilled_at) / self.token_interval();
+        self.tokens = self.tokens.saturating_add(new_tokens);
+
+        if self.tokens >= self.limit.max_runs {
+            self.tokens = self.limit.max_runs;
+            self.refilled_at = timestamp;
+        } else {
+            self.refilled_at += new_tokens * self.token_interval();
+        }
+    }
+
+    #[inline(always)]
+    fn token_interval(&self) -> u64 {
+        (self.limit.period_nano / self.limit.max_runs.max(1)).max(1)
+    }
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize)]
+pub struct ThrottleState {
+    pub last_run_at: u64,
+    pub task_id: TaskId,
+    /// When the window after `last_run_at` is over, the state is pruned after that
+    pub window_ends_at: Option<u64>,
+}
+
+impl ThrottleState {
+    #[inline(always)]
+    pub fn is_expired(&self, timestamp: u64) -> bool {
+        self.window_ends_at.unwrap_or(self.last_run_at) <= timestamp
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum RunOutcome {
+    /// The task was returned for execution, but its result wasn't reported yet
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RunLogEntry {
+    pub task_id: TaskId,
+    /// When the run was due, including jitter and spread
+    pub planned_at: u64,
+    pub executed_at: u64,
+    pub lateness_nano: u64,
+    pub outcome: RunOutcome,
+    pub error: Option<String>,
+}
+
+/// A ring buffer of the most recent runs, the oldest runs are evicted once `capacity` is reached
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RunLog {
+    capacity: u64,
+    entries: VecDeque<RunLogEntry>,
+}
+
+impl RunLog {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, entry: RunLogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.truncate(self.capacity - 1);
+        self.entries.push_back(entry);
+    }
+
+    /// Sets the outcome of the most recent pending run of the task
+    pub fn set_outcome(
+        &mut self,
+        task_id: TaskId,
+        outcome: RunOutcome,
+        error: Option<String>,
+    ) -> bool {
+        let entry = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|it| it.task_id == task_id && it.outcome == RunOutcome::Pending);
+
+        match entry {
+            Some(entry) => {
+                entry.outcome = outcome;
+                entry.error = error;
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs from the oldest to the most recent one
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &RunLogEntry> {
+        self.entries.iter()
+    }
+
+    #[inline(always)]
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.truncate(capacity);
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn truncate(&mut self, len: u64) {
+        while self.entries.len() as u64 > len {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Upper bounds of lateness buckets: 1s, 2s, 5s, 10s, 30s, 1m, 5m
+pub const DEFAULT_LATENESS_BUCKETS_NANO: [u64; 7] = [
+    1_000_000_000,
+    2_000_000_000,
+    5_000_000_000,
+    10_000_000_000,
+    30_000_000_000,
+    NANOS_IN_MINUTE,
+    NANOS_IN_MINUTE * 5,
+];
+
+/// `counts[i]` is the number of observed values which are not greater than `bounds[i]` (and greater than
+/// the previous bound), the last count is for values greater than any bound
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Histogram {
+    pub bounds: Vec<u64>,
+    pub counts: Vec<u64>,
+    pub sum: u64,
+    pub total: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<u64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0,
+            total: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: u64) {
+        let idx = self.bounds.partition_point(|bound| *bound < value);
+
+        self.counts[idx] += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.total += 1;
+    }
+}
+
+/// Counters of all runs since the scheduler was created
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RunCounters {
+    pub executed: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub lateness_nano: Histogram,
+}
+
+impl Default for RunCounters {
+    fn default() -> Self {
+        Self {
+            executed: 0,
+            succeeded: 0,
+            failed: 0,
+            lateness_nano: Histogram::new(DEFAULT_LATENESS_BUCKETS_NANO.to_vec()),
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CronMetrics {
+    pub tasks: u64,
+    pub paused_tasks: u64,
+    pub quarantined_tasks: u64,
+    /// How many `FixedDelay` tasks were handed out, but weren't completed yet
+    pub awaiting_completion_tasks: u64,
+    /// How many runs are queued
+    pub queue_length: u64,
+    /// How many queued runs are already due, but weren't executed yet
+    pub backlog: u64,
+    pub runs: RunCounters,
+}
+
+impl CronMetrics {
+    /// Renders metrics in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP ic_cron_tasks Number of scheduled tasks\n");
+        out.push_str("# TYPE ic_cron_tasks gauge\n");
+        out.push_str(&format!("ic_cron_tasks {}\n", self.tasks));
+
+        out.push_str("# HELP ic_cron_tasks_by_state Number of scheduled tasks in a state\n");
+        out.push_str("# TYPE ic_cron_tasks_by_state gauge\n");
+        out.push_str(&format!(
+            "ic_cron_tasks_by_state{{state=\"paused\"}} {}\n",
+            self.paused_tasks
+        ));
+        out.push_str(&format!(
+            "ic_cron_tasks_by_state{{state=\"quarantined\"}} {}\n",
+            self.quarantined_tasks
+        ));
+        out.push_str(&format!(
+            "ic_cron_tasks_by_state{{state=\"awaiting_completion\"}} {}\n",
+            self.awaiting_completion_tasks
+        ));
+
+        out.push_str("# HELP ic_cron_queue_length Number of queued runs\n");
+        out.push_str("# TYPE ic_cron_queue_length gauge\n");
+        out.push_str(&format!("ic_cron_queue_length {}\n", self.queue_length));
+
+        out.push_str("# HELP ic_cron_backlog Number of due runs which weren't executed yet\n");
+        out.push_str("# TYPE ic_cron_backlog gauge\n");
+        out.push_str(&format!("ic_cron_backlog {}\n", self.backlog));
+
+        out.push_str("# HELP ic_cron_runs_total Number of runs by kind\n");
+        out.push_str("# TYPE ic_cron_runs_total counter\n");
+        for (kind, count) in [
+            ("executed", self.runs.executed),
+            ("succeeded", self.runs.succeeded),
+            ("failed", self.runs.failed),
+        ] {
+            out.push_str(&format!(
+                "ic_cron_runs_total{{kind=\"{}\"}} {}\n",
+                kind, count
+            ));
+        }
+
+        let lateness = &self.runs.lateness_nano;
+        let to_seconds = |nanos: u64| nanos as f64 / 1_000_000_000f64;
+
+        out.push_str("# HELP ic_cron_run_lateness_seconds How late runs were executed\n");
+        out.push_str("# TYPE ic_cron_run_lateness_seconds histogram\n");
+
+        let mut cumulative = 0;
+        for (bound, count) in lateness.bounds.iter().zip(lateness.counts.iter()) {
+            cumulative += count;
+            out.push_str(&format!(
+                "ic_cron_run_lateness_seconds_bucket{{le=\"{}\"}} {}\n",
+                to_seconds(*bound),
+                cumulative
+            ));
+        }
+        out.push_str(&format!(
+            "ic_cron_run_lateness_seconds_bucket{{le=\"+Inf\"}} {}\n",
+            lateness.total
+        ));
+        out.push_str(&format!(
+            "ic_cron_run_lateness_seconds_sum {}\n",
+            to_seconds(lateness.sum)
+        ));
+        out.push_str(&format!(
+            "ic_cron_run_lateness_seconds_count {}\n",
+            lateness.total
+        ));
+
+        out
     }
 
-    pub fn set_payload<T: CandidType>(&mut self, payload: T) {
-        self.payload.data = encode_one(payload).unwrap()
+    /// Serves `render_prometheus()` at `/metrics`, responds with `404` to any other path
+    pub fn http_response(&self, request: &HttpRequest) -> HttpResponse {
+        match request.url.split('?').next() {
+            Some("/metrics") => HttpResponse {
+                status_code: 200,
+                headers: vec![(
+                    String::from("Content-Type"),
+                    String::from("text/plain; version=0.0.4"),
+                )],
+                body: self.render_prometheus().into_bytes(),
+            },
+            _ => HttpResponse {
+                status_code: 404,
+                headers: vec![],
+                body: b"Not found".to_vec(),
+            },
+        }
     }
 }
 
+#[derive(Clone, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
 #[derive(CandidType, Deserialize, Clone, Copy)]
 pub struct TaskTimestamp {
     pub task_id: TaskId,
@@ -86,98 +1071,165 @@ impl Eq for TaskTimestamp {}
 
 impl PartialOrd for TaskTimestamp {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.timestamp
-            .partial_cmp(&other.timestamp)
-            .map(|it| it.reverse())
-    }
-
-    fn lt(&self, other: &Self) -> bool {
-        self.timestamp.gt(&other.timestamp)
-    }
-
-    fn le(&self, other: &Self) -> bool {
-        self.timestamp.ge(&other.timestamp)
-    }
-
-    fn gt(&self, other: &Self) -> bool {
-        self.timestamp.lt(&other.timestamp)
-    }
-
-    fn ge(&self, other: &Self) -> bool {
-        self.timestamp.le(&other.timestamp)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for TaskTimestamp {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.timestamp.cmp(&other.timestamp).reverse()
+        self.timestamp
+            .cmp(&other.timestamp)
+            .then_with(|| self.task_id.cmp(&other.task_id))
+            .reverse()
     }
+}
 
-    fn max(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        max(self, other)
-    }
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ReadyTask {
+    pub priority: u32,
+    pub task: TaskTimestamp,
+}
 
-    fn min(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        min(self, other)
+impl PartialOrd for ReadyTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
+}
 
-    fn clamp(self, min: Self, max: Self) -> Self
-    where
-        Self: Sized,
-    {
-        if self.timestamp < max.timestamp {
-            max
-        } else if self.timestamp > min.timestamp {
-            min
-        } else {
-            self
-        }
+impl Ord for ReadyTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| self.task.cmp(&other.task))
     }
 }
 
-#[derive(Default, Deserialize, Clone)]
-pub struct TaskExecutionQueue(BinaryHeap<TaskTimestamp>);
+/// Tasks wait in `pending` until their timestamp comes and are then promoted to `ready`, where they
+/// are ordered by priority. Tasks that didn't fit into a tick's budget stay in `ready` for the next one.
+///
+/// Ready tasks are split into lanes (one per namespace with fair scheduling, a single one otherwise),
+/// which take turns in a weighted round-robin.
+#[derive(Default, Clone)]
+pub struct TaskExecutionQueue {
+    pending: BinaryHeap<TaskTimestamp>,
+    ready: BTreeMap<String, BinaryHeap<ReadyTask>>,
+    current_lane: Option<String>,
+    lane_credits: u32,
+}
 
 impl TaskExecutionQueue {
     #[inline(always)]
     pub fn push(&mut self, task: TaskTimestamp) {
-        self.0.push(task);
+        self.pending.push(task);
     }
 
     pub fn pop_ready(&mut self, timestamp: u64) -> Vec<TaskTimestamp> {
-        let mut cur = self.0.peek();
-        if cur.is_none() {
-            return Vec::new();
-        }
+        self.promote_ready(timestamp, |_| (0, String::new()), || true);
 
         let mut result = vec![];
 
-        while cur.unwrap().timestamp <= timestamp {
-            result.push(self.0.pop().unwrap());
+        while let Some(it) = self.pop_next(|_| 1) {
+            result.push(it);
+        }
+
+        result
+    }
 
-            cur = self.0.peek();
-            if cur.is_none() {
+    /// `classify` returns a priority and a lane of the task, promotion stops once `has_budget`
+    /// returns `false`
+    pub fn promote_ready<F, B>(&mut self, timestamp: u64, classify: F, mut has_budget: B)
+    where
+        F: Fn(TaskId) -> (u32, String),
+        B: FnMut() -> bool,
+    {
+        while let Some(cur) = self.pending.peek() {
+            if cur.timestamp > timestamp || !has_budget() {
                 break;
             }
+
+            let task = self.pending.pop().unwrap();
+            let (priority, lane) = classify(task.task_id);
+
+            self.ready
+                .entry(lane)
+                .or_default()
+                .push(ReadyTask { priority, task });
         }
+    }
 
-        result
+    /// `weight_of` returns how many tasks in a row a lane can pop before it's the next lane's turn
+    pub fn pop_next<F: Fn(&str) -> u32>(&mut self, weight_of: F) -> Option<TaskTimestamp> {
+        let current_lane = self
+            .current_lane
+            .as_ref()
+            .filter(|lane| self.lane_credits > 0 && self.ready.contains_key(*lane))
+            .cloned();
+
+        let lane = match current_lane {
+            Some(lane) => lane,
+            None => {
+                let next_lane = match &self.current_lane {
+                    Some(lane) => self
+                        .ready
+                        .range::<String, _>((Bound::Excluded(lane), Bound::Unbounded))
+                        .next()
+                        .map(|(lane, _)| lane.clone()),
+                    None => None,
+                };
+
+                let lane = next_lane.or_else(|| self.ready.keys().next().cloned())?;
+
+                self.lane_credits = weight_of(&lane).max(1);
+                self.current_lane = Some(lane.clone());
+
+                lane
+            }
+        };
+
+        let tasks = self.ready.get_mut(&lane).unwrap();
+        let task = tasks.pop().map(|it| it.task);
+
+        if tasks.is_empty() {
+            self.ready.remove(&lane);
+        }
+
+        self.lane_credits -= 1;
+
+        task
+    }
+
+    /// All queued runs in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &TaskTimestamp> {
+        self.ready
+            .values()
+            .flatten()
+            .map(|it| &it.task)
+            .chain(self.pending.iter())
+    }
+
+    pub fn remove(&mut self, task_id: TaskId) {
+        self.pending.retain(|it| it.task_id != task_id);
+
+        for tasks in self.ready.values_mut() {
+            tasks.retain(|it| it.task.task_id != task_id);
+        }
+
+        self.ready.retain(|_, tasks| !tasks.is_empty());
+    }
+
+    #[inline(always)]
+    pub fn ready_len(&self) -> usize {
+        self.ready.values().map(|tasks| tasks.len()).sum()
     }
 
     #[inline(always)]
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.pending.is_empty() && self.ready.is_empty()
     }
 
     #[inline(always)]
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.pending.len() + self.ready_len()
     }
 }
 
@@ -194,6 +1246,21 @@ impl CandidType for TaskExecutionQueue {
     where
         S: Serializer,
     {
-        self.clone().0.into_sorted_vec().idl_serialize(serializer)
+        let mut tasks: Vec<_> = self.iter().copied().collect();
+        tasks.sort();
+
+        tasks.idl_serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskExecutionQueue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self {
+            pending: BinaryHeap::deserialize(deserializer)?,
+            ..Default::default()
+        })
     }
 }